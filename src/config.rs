@@ -0,0 +1,117 @@
+//! Per-project settings read from a `.collclean.toml` file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use collclean::Rule;
+use serde::{Deserialize, Deserializer};
+
+pub const FILE_NAME: &str = ".collclean.toml";
+
+/// Settings of a `.collclean.toml` file. Each setting corresponds to the command-line option of
+/// the same name, which overrides it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub unwrap: Vec<String>,
+    pub drop: Vec<String>,
+    #[serde(deserialize_with = "rules")]
+    pub rules: Vec<Rule>,
+    pub env: Vec<String>,
+    pub drop_env: Vec<String>,
+    pub verbatim_env: Vec<String>,
+    pub color: Option<Colors>,
+    pub accept: bool,
+    pub reject: bool,
+    pub keep_optional: bool,
+    pub strip_dead: bool,
+    pub strip_comments: bool,
+    // default output behavior
+    pub dry: bool,
+    pub diff: bool,
+    pub context: Option<usize>,
+    pub format: Option<Format>,
+}
+
+/// Colors of `--color`, either `true` for all colors or a list of colors.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Colors {
+    All(bool),
+    Only(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Parses rules like `textcolor:drop,keep`, as given to `--rule`.
+fn rules<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Rule>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|rule| rule.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Finds `.collclean.toml` in `dir` or the closest of its parent directories.
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|file| file.is_file())
+}
+
+pub fn load(path: &Path) -> Result<Config> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("Invalid configuration in {}", path.display()))
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            unwrap = ["alice", "bob"]
+            drop = ["todo"]
+            rules = ["textcolor:drop,keep"]
+            verbatim-env = ["code"]
+            color = ["blue"]
+            strip-comments = true
+            dry = true
+            format = "json"
+            "#,
+        )?;
+        assert_eq!(config.unwrap, ["alice", "bob"]);
+        assert_eq!(config.drop, ["todo"]);
+        assert_eq!(config.rules[0].name, "textcolor");
+        assert_eq!(config.verbatim_env, ["code"]);
+        assert_eq!(config.color, Some(Colors::Only(vec!["blue".to_string()])));
+        assert!(config.strip_comments && config.dry && !config.strip_dead);
+        assert_eq!(config.format, Some(Format::Json));
+
+        let config: Config = toml::from_str("color = true")?;
+        assert_eq!(config.color, Some(Colors::All(true)));
+        assert!(toml::from_str::<Config>("rules = [\"textcolor\"]").is_err());
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+        assert!(toml::from_str::<Config>("format = \"xml\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find() -> Result<()> {
+        let dir = TempDir::new("config")?;
+        let file = dir.write(FILE_NAME, "unwrap = [\"alice\"]")?;
+        std::fs::create_dir_all(dir.join("chapters/part"))?;
+
+        assert_eq!(find(&dir.join("chapters/part")), Some(file.clone()));
+        assert_eq!(load(&file)?.unwrap, ["alice"]);
+        Ok(())
+    }
+}
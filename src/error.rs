@@ -0,0 +1,492 @@
+//! Errors for texts which cannot be cleaned safely.
+
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use yansi::{Color, Paint, Style};
+
+/// A 0-indexed position in a text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// Byte offset.
+    pub offset: usize,
+    /// Line, counted by `\n`.
+    pub line: usize,
+    /// Column in characters.
+    pub column: usize,
+}
+
+impl Location {
+    /// Location of the byte `offset` in `text`.
+    pub fn at(text: &str, offset: usize) -> Self {
+        let line_start = text[..offset].rfind('\n').map_or(0, |p| p + 1);
+        Location {
+            offset,
+            line: text[..offset].matches('\n').count(),
+            column: text[line_start..offset].chars().count(),
+        }
+    }
+}
+
+/// The reason why a text cannot be cleaned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// `}` without opening `{`.
+    UnmatchedClosingBrace,
+    /// `{` without closing `}`.
+    UnclosedBrace,
+    /// Argument of this command without closing `}`.
+    UnclosedCommand(String),
+    /// This command has fewer arguments than its rule.
+    MissingArgument(String),
+    /// `\end{name}` which does not match the last `\begin`.
+    UnmatchedEnd(String),
+    /// `\begin{name}` without `\end{name}`.
+    UnclosedEnvironment(String),
+    /// `\verb` which is not closed on its line.
+    UnclosedVerb,
+    /// `\iffalse` without `\fi`.
+    UnclosedConditional,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnmatchedClosingBrace => {
+                write!(f, "closing bracket without opening counterpart")
+            }
+            ErrorKind::UnclosedBrace => write!(f, "opening bracket without closing counterpart"),
+            ErrorKind::UnclosedCommand(name) => {
+                write!(f, "argument of \\{name} has no closing bracket")
+            }
+            ErrorKind::MissingArgument(name) => write!(f, "\\{name} is missing an argument"),
+            ErrorKind::UnmatchedEnd(name) => {
+                write!(f, "\\end{{{name}}} without matching \\begin{{{name}}}")
+            }
+            ErrorKind::UnclosedEnvironment(name) => {
+                write!(f, "\\begin{{{name}}} has no matching \\end{{{name}}}")
+            }
+            ErrorKind::UnclosedVerb => write!(f, "\\verb is not closed on its line"),
+            ErrorKind::UnclosedConditional => write!(f, "\\iffalse has no matching \\fi"),
+        }
+    }
+}
+
+/// The line around `span` in `text`, without its line break, and the location and number of
+/// characters of `span` on this line. At least one character is highlighted.
+pub(crate) fn highlight(text: &str, span: Range<usize>) -> (Location, String, usize) {
+    let line_start = text[..span.start].rfind('\n').map_or(0, |p| p + 1);
+    let line_end = text[span.start..]
+        .find('\n')
+        .map_or(text.len(), |p| span.start + p);
+    let line = text[line_start..line_end].trim_end_matches('\r');
+    let end = span.end.clamp(span.start, line_start + line.len());
+    let len = text[span.start..end].chars().count().max(1);
+    (Location::at(text, span.start), line.to_string(), len)
+}
+
+/// A location related to an error, e.g. the `\begin` of an unmatched `\end`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub message: String,
+    /// Start of the related text.
+    pub location: Location,
+    /// The line of `location`, without its line break.
+    pub snippet: String,
+    /// Number of highlighted characters at `location`.
+    pub len: usize,
+}
+
+impl Note {
+    /// Note about the bytes `span` of `text`.
+    pub fn new(message: impl Into<String>, text: &str, span: Range<usize>) -> Self {
+        let (location, snippet, len) = highlight(text, span);
+        Note {
+            message: message.into(),
+            location,
+            snippet,
+            len,
+        }
+    }
+}
+
+/// An error found while scanning a text, rendered like a compiler diagnostic: a
+/// `file:line:col: error: message` header followed by the offending line and notes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Start of the offending text.
+    pub location: Location,
+    /// The file the text was read from, if known.
+    pub file: Option<PathBuf>,
+    /// The line of `location`, without its line break.
+    pub snippet: String,
+    /// Number of highlighted characters at `location`.
+    pub len: usize,
+    /// Related locations, e.g. the `\begin` of an unmatched `\end`.
+    pub notes: Vec<Note>,
+}
+
+impl Error {
+    /// Error about the bytes `span` of `text`.
+    pub fn new(kind: ErrorKind, text: &str, span: Range<usize>) -> Self {
+        let (location, snippet, len) = highlight(text, span);
+        Error {
+            kind,
+            location,
+            file: None,
+            snippet,
+            len,
+            notes: vec![],
+        }
+    }
+
+    /// Attaches the file the text was read from.
+    pub fn in_file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: Note) -> Self {
+        self.notes.push(note);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let diagnostic = Diagnostic {
+            level: Level::Error,
+            message: self.kind.to_string(),
+            file: self.file.as_deref(),
+            location: self.location,
+            snippet: &self.snippet,
+            len: self.len,
+            notes: &self.notes,
+        };
+        write!(f, "{diagnostic}")
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// All errors found in a text, sorted by their location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Errors(pub Vec<Error>);
+
+impl Errors {
+    /// Attaches the file the text was read from to all errors.
+    pub fn in_file(self, file: impl Into<PathBuf>) -> Self {
+        let file = file.into();
+        Errors(
+            self.0
+                .into_iter()
+                .map(|error| error.in_file(file.clone()))
+                .collect(),
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Error> {
+        self.0.iter()
+    }
+}
+
+impl From<Error> for Errors {
+    fn from(error: Error) -> Self {
+        Errors(vec![error])
+    }
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Errors {}
+
+/// A problem which does not stop cleaning, e.g. an included file which is not found, rendered
+/// like an error but as a `warning`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub message: String,
+    /// Start of the text the warning is about.
+    pub location: Location,
+    /// The file the text was read from, if known.
+    pub file: Option<PathBuf>,
+    /// The line of `location`, without its line break.
+    pub snippet: String,
+    /// Number of highlighted characters at `location`.
+    pub len: usize,
+}
+
+impl Warning {
+    /// Warning about the bytes `span` of `text`.
+    pub fn new(message: impl Into<String>, text: &str, span: Range<usize>) -> Self {
+        let (location, snippet, len) = highlight(text, span);
+        Warning {
+            message: message.into(),
+            location,
+            file: None,
+            snippet,
+            len,
+        }
+    }
+
+    /// Attaches the file the text was read from.
+    pub fn in_file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let diagnostic = Diagnostic {
+            level: Level::Warning,
+            message: self.message.clone(),
+            file: self.file.as_deref(),
+            location: self.location,
+            snippet: &self.snippet,
+            len: self.len,
+            notes: &[],
+        };
+        write!(f, "{diagnostic}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Error,
+    Warning,
+}
+
+/// A message about a location in a file, rendered like a compiler diagnostic:
+///
+/// ```text
+/// paper.tex:3:1: error: \end{alice} without matching \begin{alice}
+///   |
+/// 3 | \end{alice}
+///   | ^^^^^^^^^^^
+///   |
+/// 1 | \begin{bob}
+///   | ----------- note: the last open environment is \begin{bob}
+/// ```
+struct Diagnostic<'a> {
+    pub level: Level,
+    pub message: String,
+    pub file: Option<&'a Path>,
+    pub location: Location,
+    // the line of `location`, without its line break
+    pub snippet: &'a str,
+    // number of highlighted characters at `location`
+    pub len: usize,
+    pub notes: &'a [Note],
+}
+
+impl Level {
+    fn style(self) -> Style {
+        match self {
+            Level::Error => Color::Red.bold(),
+            Level::Warning => Color::Yellow.bold(),
+        }
+    }
+}
+
+/// Writes `snippet` with `marker` below the character at `location`, followed by `label`.
+fn write_snippet(
+    f: &mut fmt::Formatter<'_>,
+    width: usize,
+    location: Location,
+    snippet: &str,
+    marker: &str,
+    label: &str,
+    style: Style,
+) -> fmt::Result {
+    let gutter = Color::Blue.bold();
+    let bar = "|".paint(gutter);
+    let number = (location.line + 1).to_string();
+    writeln!(f)?;
+    writeln!(f, "{:width$} {bar}", "")?;
+    writeln!(f, "{:>width$} {bar} {snippet}", number.paint(gutter))?;
+    // keep tabs so that the markers line up with the snippet
+    let indent: String = snippet
+        .chars()
+        .take(location.column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    write!(
+        f,
+        "{:width$} {bar} {indent}{}{}",
+        "",
+        marker.paint(style),
+        label.paint(style)
+    )
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        let level = match self.level {
+            Level::Error => "error:",
+            Level::Warning => "warning:",
+        };
+        write!(
+            f,
+            "{}:{}: {} {}",
+            self.location.line + 1,
+            self.location.column + 1,
+            level.paint(self.level.style()),
+            self.message.bold()
+        )?;
+
+        let width = std::iter::once(self.location.line)
+            .chain(self.notes.iter().map(|note| note.location.line))
+            .max()
+            .map_or(1, |line| (line + 1).to_string().len());
+        let marker = "^".repeat(self.len);
+        let style = self.level.style();
+        write_snippet(f, width, self.location, self.snippet, &marker, "", style)?;
+        for note in self.notes {
+            let marker = "-".repeat(note.len);
+            let label = format!(" note: {}", note.message);
+            let style = Color::Blue.bold();
+            write_snippet(
+                f,
+                width,
+                note.location,
+                &note.snippet,
+                &marker,
+                &label,
+                style,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        let text = "ab\nä c\n";
+        assert_eq!(
+            Location::at(text, 6),
+            Location {
+                offset: 6,
+                line: 1,
+                column: 2
+            }
+        );
+        assert_eq!(Location::at(text, 0).column, 0);
+        assert_eq!(Location::at(text, text.len()).line, 2);
+    }
+
+    /// Removes the colors from a rendered diagnostic.
+    fn plain(rendered: &str) -> String {
+        let mut plain = String::new();
+        let mut rest = rendered;
+        while let Some(escape) = rest.find('\x1b') {
+            plain.push_str(&rest[..escape]);
+            rest = &rest[escape..];
+            rest = &rest[rest.find('m').map_or(rest.len(), |m| m + 1)..];
+        }
+        plain + rest
+    }
+
+    #[test]
+    fn test_highlight() {
+        let text = "a\n\\alice{b\r\nc}";
+        let (location, line, len) = highlight(text, 2..9);
+        assert_eq!((location.line, location.column), (1, 0));
+        assert_eq!((line.as_str(), len), ("\\alice{b", 7));
+        // spans are cut at the end of the line, but highlight at least one character
+        assert_eq!(highlight(text, 2..text.len()).2, 8);
+        assert_eq!(highlight(text, 1..1).2, 1);
+        assert_eq!(highlight(text, text.len()..text.len()).1, "c}");
+    }
+
+    #[test]
+    fn test_display() {
+        let text = "x\n  }\r\ny";
+        let error = Error::new(ErrorKind::UnmatchedClosingBrace, text, 4..5);
+        assert_eq!(
+            plain(&error.to_string()),
+            "2:3: error: closing bracket without opening counterpart\n  \
+             |\n\
+             2 |   }\n  \
+             |   ^"
+        );
+        let error = Error::new(ErrorKind::UnclosedCommand("alice".to_string()), text, 0..1)
+            .in_file("paper.tex");
+        assert!(plain(&error.to_string())
+            .starts_with("paper.tex:1:1: error: argument of \\alice has no closing bracket\n"));
+    }
+
+    #[test]
+    fn test_display_notes() {
+        let text = "\\begin{bob}\n\tx\n\n\n\n\n\n\n\n\t\\end{alice}";
+        let error =
+            Error::new(ErrorKind::UnmatchedEnd("alice".to_string()), text, 23..34).with_note(
+                Note::new("the last open environment is \\begin{bob}", text, 0..11),
+            );
+        assert_eq!(
+            plain(&error.to_string()),
+            "10:2: error: \\end{alice} without matching \\begin{alice}\n   \
+             |\n\
+             10 | \t\\end{alice}\n   \
+             | \t^^^^^^^^^^^\n   \
+             |\n \
+             1 | \\begin{bob}\n   \
+             | ----------- note: the last open environment is \\begin{bob}"
+        );
+    }
+
+    #[test]
+    fn test_display_warning() {
+        let text = "\\input{missing}";
+        let warning =
+            Warning::new("included file missing not found", text, 0..15).in_file("main.tex");
+        assert_eq!(
+            plain(&warning.to_string()),
+            "main.tex:1:1: warning: included file missing not found\n  \
+             |\n\
+             1 | \\input{missing}\n  \
+             | ^^^^^^^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let text = "{a\n{";
+        let errors = Errors(vec![
+            Error::new(ErrorKind::UnclosedBrace, text, 0..1),
+            Error::new(ErrorKind::UnclosedBrace, text, 3..4),
+        ])
+        .in_file("paper.tex");
+        assert!(errors.iter().all(|error| error.file.is_some()));
+        let rendered = plain(&errors.to_string());
+        let headers: Vec<&str> = rendered
+            .lines()
+            .filter(|line| line.contains(": error:"))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                "paper.tex:1:1: error: opening bracket without closing counterpart",
+                "paper.tex:2:1: error: opening bracket without closing counterpart",
+            ]
+        );
+    }
+}
@@ -0,0 +1,3130 @@
+//! Cleans LaTeX files after a collaboration by removing markup commands such as `\alice{...}`.
+//!
+//! ```
+//! use collclean::Cleaner;
+//!
+//! let cleaner = Cleaner::new().unwrap_command("alice").drop_command("todo");
+//! let cleaned = cleaner.clean("\\alice{new} text\\todo{check}").unwrap();
+//! assert_eq!(cleaned.text, "new text");
+//! assert_eq!(cleaned.removed, 2);
+//! ```
+
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+mod error;
+#[cfg(test)]
+mod testing;
+pub mod tokenizer;
+
+pub use error::{Error, ErrorKind, Errors, Location, Note, Warning};
+
+use tokenizer::{Token, TokenKind, Tokenizer};
+
+/// Builder describing which markup is removed from a text.
+#[derive(Debug, Clone, Default)]
+pub struct Cleaner {
+    rules: Vec<Rule>,
+    environments: Vec<Environment>,
+    // keep the content of optional arguments of unwrapped commands
+    keep_optional: bool,
+    changes: Option<Changes>,
+    colors: Option<Vec<String>>,
+    strip_dead: bool,
+    strip_comments: bool,
+    verbatim: Vec<String>,
+    // 1-indexed, inclusive line range
+    from: Option<usize>,
+    to: Option<usize>,
+}
+
+/// How changes tracked with the `changes` package are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Changes {
+    Accept,
+    Reject,
+}
+
+/// The result of cleaning a text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cleaned {
+    /// The cleaned text.
+    pub text: String,
+    /// Removed ranges of the original text, sorted by their start.
+    pub deletions: Vec<Deletion>,
+    /// Removed markup with its deletions, sorted by its start.
+    pub occurrences: Vec<Occurrence>,
+    /// Number of removed commands, environments and other markup.
+    pub removed: usize,
+}
+
+impl Cleaner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes the command `\name{...}` but keeps its content.
+    pub fn unwrap_command(self, name: &str) -> Self {
+        self.rule(Rule::new(name, Mode::Unwrap))
+    }
+
+    /// Removes the command `\name{...}` together with its content.
+    pub fn drop_command(self, name: &str) -> Self {
+        self.rule(Rule::new(name, Mode::Drop))
+    }
+
+    /// Adds a rule, e.g., for a command with several arguments.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Removes `\begin{name}` and `\end{name}` but keeps the content.
+    pub fn unwrap_environment(mut self, name: &str) -> Self {
+        self.environments.push(Environment::new(name, Mode::Unwrap));
+        self
+    }
+
+    /// Removes the environment `name` together with its content.
+    pub fn drop_environment(mut self, name: &str) -> Self {
+        self.environments.push(Environment::new(name, Mode::Drop));
+        self
+    }
+
+    /// Keeps the content of optional arguments `[...]` of unwrapped commands.
+    pub fn keep_optional(mut self, keep: bool) -> Self {
+        self.keep_optional = keep;
+        self
+    }
+
+    /// Accepts all changes tracked with the `changes` package.
+    pub fn accept_changes(mut self) -> Self {
+        self.changes = Some(Changes::Accept);
+        self
+    }
+
+    /// Rejects all changes tracked with the `changes` package.
+    pub fn reject_changes(mut self) -> Self {
+        self.changes = Some(Changes::Reject);
+        self
+    }
+
+    /// Removes groups `{\color{name} ...}` and `\textcolor{name}{...}` but keeps the text. Only
+    /// the given colors are removed, or all colors if there are none.
+    pub fn colors<S: Into<String>>(mut self, colors: impl IntoIterator<Item = S>) -> Self {
+        self.colors = Some(colors.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Removes `\iffalse ... \fi` blocks and comment environments.
+    pub fn strip_dead(mut self, strip: bool) -> Self {
+        self.strip_dead = strip;
+        self
+    }
+
+    /// Removes comments starting with `%`.
+    pub fn strip_comments(mut self, strip: bool) -> Self {
+        self.strip_comments = strip;
+        self
+    }
+
+    /// Does not parse the content of this environment, like for `verbatim`.
+    pub fn verbatim_environment(mut self, name: &str) -> Self {
+        self.verbatim.push(name.to_string());
+        self
+    }
+
+    /// Only removes markup between these lines (1-indexed, inclusive).
+    pub fn lines(mut self, from: Option<usize>, to: Option<usize>) -> Self {
+        self.from = from;
+        self.to = to;
+        self
+    }
+
+    /// Whether there is no markup to remove.
+    pub fn is_empty(&self) -> bool {
+        self.markup().is_empty()
+    }
+
+    fn markup(&self) -> Markup {
+        let mut rules = self.rules.clone();
+        if self.keep_optional {
+            // commands which are dropped entirely lose their optional arguments in any case
+            for rule in rules
+                .iter_mut()
+                .filter(|rule| rule.args.contains(&Mode::Unwrap))
+            {
+                rule.optional = Some(Mode::Unwrap);
+            }
+        }
+        match self.changes {
+            Some(Changes::Accept) => rules.extend(accept_changes()),
+            Some(Changes::Reject) => rules.extend(reject_changes()),
+            None => {}
+        }
+        if let Some(colors) = &self.colors {
+            rules.push(Rule::textcolor(colors));
+        }
+        Markup {
+            rules,
+            environments: self.environments.clone(),
+            colors: self.colors.clone(),
+            strip_dead: self.strip_dead,
+            strip_comments: self.strip_comments,
+            verbatim: self.verbatim.clone(),
+        }
+    }
+
+    /// Finds the ranges of `text` which are removed, without changing it.
+    pub fn deletions(&self, text: &str) -> Result<Vec<Deletion>, Errors> {
+        find_deletions(text, &self.markup(), self.from, self.to)
+    }
+
+    /// Finds the markup in `text` which is removed, without changing it.
+    pub fn occurrences(&self, text: &str) -> Result<Vec<Occurrence>, Errors> {
+        find_occurrences(text, &self.markup(), self.from, self.to)
+    }
+
+    /// Cleans `text`. Fails without changes if the brackets or environments are unbalanced.
+    pub fn clean(&self, text: &str) -> Result<Cleaned, Errors> {
+        Ok(Cleaned::new(text, self.occurrences(text)?))
+    }
+}
+
+impl Cleaned {
+    /// Removes `occurrences`, e.g. a selection of those found by `Cleaner::occurrences`, from
+    /// `text`.
+    pub fn new(text: &str, occurrences: Vec<Occurrence>) -> Self {
+        let mut deletions: Vec<Deletion> = occurrences
+            .iter()
+            .flat_map(|occurrence| occurrence.deletions.iter().cloned())
+            .collect();
+        deletions.sort();
+        let mut cleaned = text.to_string();
+        let removed = clean_text(&mut cleaned, deletions.clone());
+        Cleaned {
+            text: cleaned,
+            deletions,
+            occurrences,
+            removed,
+        }
+    }
+}
+
+/// A removed range `start..=end` (in bytes) of a text.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Ord, Eq)]
+pub struct Deletion {
+    /// Byte offset of the first removed byte.
+    pub start: usize,
+    /// Byte offset of the last removed byte.
+    pub end: usize,
+    /// 0-indexed line of `start`.
+    pub line: usize,
+    pub kind: Kind,
+}
+
+/// The part of the markup a deletion removes.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq)]
+pub enum Kind {
+    /// Command name and opening bracket.
+    Opening,
+    /// Brackets between two arguments.
+    Between,
+    /// Closing bracket of a command.
+    Closing,
+    /// Command including its content.
+    Whole,
+}
+
+/// A removed command, environment or other markup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    /// Name of the command or environment, `iffalse` for dead text and `%` for comments.
+    pub name: String,
+    /// Removed ranges, sorted by their start.
+    pub deletions: Vec<Deletion>,
+    /// Byte ranges (exclusive end) of the wrapped text, e.g., the arguments of a command or the
+    /// body of an environment.
+    pub wrapped: Vec<(usize, usize)>,
+    /// 0-indexed line of the first removed range.
+    pub line: usize,
+    /// 0-indexed line of the last removed range.
+    pub end_line: usize,
+}
+
+impl Occurrence {
+    fn new(
+        name: String,
+        mut deletions: Vec<Deletion>,
+        wrapped: Vec<(usize, usize)>,
+        line: usize,
+        end_line: usize,
+    ) -> Self {
+        deletions.sort();
+        Occurrence {
+            name,
+            deletions,
+            wrapped,
+            line,
+            end_line,
+        }
+    }
+
+    /// First removed byte.
+    pub fn start(&self) -> usize {
+        self.deletions.first().map_or(0, |deletion| deletion.start)
+    }
+
+    /// Last removed byte.
+    pub fn end(&self) -> usize {
+        self.deletions.last().map_or(0, |deletion| deletion.end)
+    }
+
+    /// The pieces of text wrapped by this markup: the kept parts, or for markup removed as a
+    /// whole, its wrapped text, e.g., the mandatory arguments of a command or the body of an
+    /// environment.
+    pub fn content<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        match self.deletions.as_slice() {
+            [whole] if whole.kind == Kind::Whole => self
+                .wrapped
+                .iter()
+                .map(|&(start, end)| &text[start..end])
+                .collect(),
+            _ => self.kept(text),
+        }
+    }
+
+    /// The parts of `text` between the removed ranges, which stay in the cleaned text.
+    pub fn kept<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.deletions
+            .windows(2)
+            .map(|pair| &text[pair[0].end + 1..pair[1].start])
+            .collect()
+    }
+}
+
+impl Deletion {
+    fn range(start: usize, end: usize, line: usize, kind: Kind) -> Self {
+        Deletion {
+            start,
+            end,
+            line,
+            kind,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+/// How a command, an argument or an environment is cleaned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Remove the command and its brackets, keep the content.
+    Unwrap,
+    /// Remove the command together with its content.
+    Drop,
+}
+
+/// Describes how a command and its arguments are cleaned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    /// Name of the command without the backslash.
+    pub name: String,
+    /// One mode per mandatory argument.
+    pub args: Vec<Mode>,
+    /// Mode for optional arguments `[...]`, which are not recognized if `None`.
+    pub optional: Option<Mode>,
+    /// Only match commands whose first argument is one of these.
+    pub filter: Option<Vec<String>>,
+}
+
+impl Rule {
+    pub fn new(name: &str, mode: Mode) -> Self {
+        Rule {
+            name: name.to_string(),
+            args: vec![mode],
+            optional: Some(Mode::Drop),
+            filter: None,
+        }
+    }
+
+    /// Rule for `\textcolor{color}{text}`, restricted to the given colors unless empty.
+    fn textcolor(colors: &[String]) -> Self {
+        Rule {
+            args: vec![Mode::Drop, Mode::Unwrap],
+            filter: (!colors.is_empty()).then(|| colors.to_vec()),
+            ..Rule::new("textcolor", Mode::Unwrap)
+        }
+    }
+
+    /// Checks the filter against the first argument, whose content starts at `arg_start`.
+    fn accepts(&self, text: &str, arg_start: usize) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        text[arg_start..]
+            .find(['{', '}'])
+            .filter(|&end| text[arg_start + end..].starts_with('}'))
+            .is_some_and(|end| {
+                filter
+                    .iter()
+                    .any(|f| f == text[arg_start..arg_start + end].trim())
+            })
+    }
+}
+
+impl std::str::FromStr for Rule {
+    type Err = anyhow::Error;
+
+    /// Parses rules of the form `name:mode,mode,...` with one mode (`keep` or `drop`) per argument.
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((name, args)) = s.split_once(':') else {
+            bail!("Rule '{s}' must have the form <command>:<keep|drop>,...");
+        };
+        let name = name.trim().trim_start_matches('\\');
+        if name.is_empty() {
+            bail!("Rule '{s}' has no command name");
+        }
+        let args = args
+            .split(',')
+            .map(|arg| match arg.trim() {
+                "keep" => Ok(Mode::Unwrap),
+                "drop" => Ok(Mode::Drop),
+                other => {
+                    bail!("Unknown argument mode '{other}' in rule '{s}' (expected keep or drop)")
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Rule {
+            args,
+            ..Rule::new(name, Mode::Unwrap)
+        })
+    }
+}
+
+/// Describes how an environment `\begin{name}...\end{name}` is cleaned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Environment {
+    pub name: String,
+    pub mode: Mode,
+}
+
+impl Environment {
+    pub fn new(name: &str, mode: Mode) -> Self {
+        Environment {
+            name: name.to_string(),
+            mode,
+        }
+    }
+}
+
+/// All markup that is removed by `find_deletions`.
+#[derive(Debug, Clone, Default)]
+struct Markup {
+    rules: Vec<Rule>,
+    environments: Vec<Environment>,
+    // colors of groups `{\color{name} ...}` to remove, where an empty list matches all colors
+    colors: Option<Vec<String>>,
+    // remove `\iffalse ... \fi` blocks and comment environments
+    strip_dead: bool,
+    // remove comments starting with `%`
+    strip_comments: bool,
+    // environments whose content is not parsed, in addition to `VERBATIM_ENVIRONMENTS`
+    verbatim: Vec<String>,
+}
+
+impl Markup {
+    fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+            && self.environments.is_empty()
+            && self.colors.is_none()
+            && !self.strip_dead
+            && !self.strip_comments
+    }
+}
+
+impl From<Vec<Rule>> for Markup {
+    fn from(rules: Vec<Rule>) -> Self {
+        Markup {
+            rules,
+            ..Default::default()
+        }
+    }
+}
+
+/// Rules accepting all changes tracked with the `changes` package.
+fn accept_changes() -> Vec<Rule> {
+    tracked_changes(Mode::Unwrap, Mode::Drop)
+}
+
+/// Rules rejecting all changes tracked with the `changes` package.
+fn reject_changes() -> Vec<Rule> {
+    tracked_changes(Mode::Drop, Mode::Unwrap)
+}
+
+fn tracked_changes(added: Mode, deleted: Mode) -> Vec<Rule> {
+    vec![
+        Rule::new("added", added),
+        Rule::new("deleted", deleted),
+        Rule {
+            args: vec![added, deleted],
+            ..Rule::new("replaced", added)
+        },
+    ]
+}
+
+/// A command whose arguments have not been closed yet.
+struct OpenCommand<'a> {
+    rule: &'a Rule,
+    // depth outside of the command
+    depth: usize,
+    start: usize,
+    line: usize,
+    // index of the current mandatory argument
+    arg: usize,
+    // start of the content of the current argument
+    arg_start: usize,
+    // content ranges (exclusive end) of the mandatory arguments read so far
+    args: Vec<(usize, usize)>,
+    // content ranges (exclusive end) which stay in the text
+    kept: Vec<(usize, usize)>,
+}
+
+impl OpenCommand<'_> {
+    /// Splits the command ending at `end` into deletions around the kept content.
+    fn deletions(&self, text: &str, end: usize) -> Vec<Deletion> {
+        let line_of = |pos: usize| self.line + text[self.start..pos].matches('\n').count();
+        let mut deletions = vec![];
+        let mut piece_start = self.start;
+        for &(keep_start, keep_end) in &self.kept {
+            let kind = if piece_start == self.start {
+                Kind::Opening
+            } else {
+                Kind::Between
+            };
+            deletions.push(Deletion::range(
+                piece_start,
+                keep_start - 1,
+                line_of(piece_start),
+                kind,
+            ));
+            piece_start = keep_end;
+        }
+        let kind = if piece_start == self.start {
+            Kind::Whole
+        } else {
+            Kind::Closing
+        };
+        deletions.push(Deletion::range(
+            piece_start,
+            end,
+            line_of(piece_start),
+            kind,
+        ));
+        deletions
+    }
+}
+
+/// An environment whose `\end` has not been found yet.
+struct OpenEnvironment {
+    environment: usize,
+    depth: usize,
+    start: usize,
+    // end of `\begin{name}`
+    end: usize,
+    line: usize,
+}
+
+/// Extends the range `start..=end` to whole lines, including the line break, if there is nothing
+/// else on these lines. This avoids empty lines, which would start a new paragraph.
+fn whole_lines(text: &str, start: usize, end: usize) -> (usize, usize) {
+    let line_start = text[..start].rfind('\n').map_or(0, |p| p + 1);
+    let line_end = end_of_line(text, end);
+    if text[line_start..start].trim().is_empty() && text[end + 1..line_end].trim().is_empty() {
+        (line_start, line_end.min(text.len() - 1))
+    } else {
+        (start, end)
+    }
+}
+
+/// Environments whose content is not parsed.
+pub const VERBATIM_ENVIRONMENTS: [&str; 6] = [
+    "verbatim",
+    "verbatim*",
+    "Verbatim",
+    "lstlisting",
+    "minted",
+    "comment",
+];
+
+/// Tokenizes `text`, reading the content of the environments in `VERBATIM_ENVIRONMENTS` and
+/// `verbatim` as single tokens.
+fn tokenize_verbatim<'a>(text: &'a str, verbatim: &[String]) -> Vec<Token<'a>> {
+    Tokenizer::new(text)
+        .verbatim_environments(
+            VERBATIM_ENVIRONMENTS
+                .into_iter()
+                .chain(verbatim.iter().map(String::as_str)),
+        )
+        .collect()
+}
+
+/// Returns the deletion of the comment starting with `%` at `pos`.
+///
+/// Comments filling a whole line are removed with the line. A `%` directly after text is kept, as
+/// it suppresses the whitespace of the line break.
+fn comment_deletion(text: &str, pos: usize, line: usize) -> Option<Deletion> {
+    let line_start = text[..pos].rfind('\n').map_or(0, |p| p + 1);
+    let line_end = end_of_line(text, pos);
+    let content_end = if text[..line_end].ends_with('\r') {
+        line_end - 1
+    } else {
+        line_end
+    };
+    let before = &text[line_start..pos];
+    let (start, end) = if before.trim().is_empty() {
+        (line_start, line_end.min(text.len() - 1))
+    } else if before.ends_with(char::is_whitespace) {
+        (line_start + before.trim_end().len(), content_end - 1)
+    } else {
+        (pos + 1, content_end - 1)
+    };
+    (start <= end).then(|| Deletion::range(start, end, line, Kind::Whole))
+}
+
+/// Inclusive byte range.
+type Span = (usize, usize);
+
+/// Index of the first token from `t` on which is not whitespace within the line.
+fn skip_spaces(tokens: &[Token], t: usize) -> usize {
+    t + tokens.get(t..).map_or(0, |rest| {
+        rest.iter()
+            .take_while(|token| token.is(TokenKind::Whitespace))
+            .count()
+    })
+}
+
+/// Returns the content of the group starting at token `t`, which must not contain nested groups,
+/// together with the index of its closing brace.
+fn group_content<'a>(text: &'a str, tokens: &[Token], t: usize) -> Option<(&'a str, usize)> {
+    let open = tokens
+        .get(t)
+        .filter(|token| token.is(TokenKind::BeginGroup))?;
+    let close = t
+        + 1
+        + tokens[t + 1..]
+            .iter()
+            .position(|token| matches!(token.kind, TokenKind::BeginGroup | TokenKind::EndGroup))?;
+    tokens[close]
+        .is(TokenKind::EndGroup)
+        .then(|| (&text[open.end..tokens[close].start], close))
+}
+
+/// Matches the command `\name` at token `t` and returns the index of the token after it.
+///
+/// TeX reads `\rev1` as the control word `\rev` followed by `1`, so a name ending with other
+/// characters than letters matches a control word followed by exactly these characters.
+fn match_name(tokens: &[Token], t: usize, name: &str) -> Option<usize> {
+    let split = name
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(name.len());
+    let (word, suffix) = name.split_at(split);
+    let token = &tokens[t];
+    if word.is_empty() || !token.is(TokenKind::ControlWord) || token.name() != word {
+        return None;
+    }
+    if suffix.is_empty() {
+        return Some(t + 1);
+    }
+    tokens
+        .get(t + 1)
+        .filter(|next| next.is(TokenKind::Text) && next.text == suffix)
+        .map(|_| t + 2)
+}
+
+/// Conditionals of TeX and e-TeX, which are closed by `\fi`.
+const CONDITIONALS: [&str; 20] = [
+    "if",
+    "ifx",
+    "ifcat",
+    "ifnum",
+    "ifdim",
+    "ifodd",
+    "ifvmode",
+    "ifhmode",
+    "ifmmode",
+    "ifinner",
+    "ifvoid",
+    "ifhbox",
+    "ifvbox",
+    "ifeof",
+    "iftrue",
+    "iffalse",
+    "ifcase",
+    "ifdefined",
+    "ifcsname",
+    "ifincsname",
+];
+
+/// Commands starting with `if` which are not closed by `\fi`: the math symbol `\iff`, `\ifthenelse`
+/// of `ifthen` and the tests of `etoolbox`, which take their branches as arguments.
+const NOT_CONDITIONALS: [&str; 10] = [
+    "iff",
+    "ifthenelse",
+    "ifblank",
+    "ifbool",
+    "iftoggle",
+    "ifboolexpr",
+    "ifboolexpe",
+    "ifinlist",
+    "ifrmnum",
+    "ifpatchable",
+];
+
+/// Prefixes of further tests of `etoolbox`, e.g., `\ifdefempty` or `\ifstrequal`.
+const NOT_CONDITIONAL_PREFIXES: [&str; 7] = [
+    "ifdef",
+    "ifundef",
+    "ifcs",
+    "ifstr",
+    "ifnum",
+    "ifdim",
+    "ifltxcounter",
+];
+
+/// Whether `\name` opens a conditional closed by `\fi`. Besides the primitives and those in
+/// `declared`, this includes the unknown conditionals of packages, e.g., `\ifpdf`.
+fn is_conditional(name: &str, declared: &[&str]) -> bool {
+    CONDITIONALS.contains(&name)
+        || declared.contains(&name)
+        || (name.starts_with("if")
+            && !NOT_CONDITIONALS.contains(&name)
+            && !NOT_CONDITIONAL_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix)))
+}
+
+/// Names of the conditionals declared with `\newif`, e.g., `ifdraft` for `\newif\ifdraft`.
+fn declared_conditionals<'a>(tokens: &[Token<'a>]) -> Vec<&'a str> {
+    tokens
+        .windows(2)
+        .filter(|pair| pair[0].is(TokenKind::ControlWord) && pair[0].name() == "newif")
+        .filter(|pair| pair[1].is(TokenKind::ControlWord))
+        .map(|pair| pair[1].name())
+        // `\iff` is the math symbol
+        .filter(|&name| name.starts_with("if") && name != "iff")
+        .collect()
+}
+
+/// Finds the `\fi` of a conditional whose body starts at token `t`, skipping nested conditionals
+/// (see `is_conditional`).
+/// Returns the token indices of a top-level `\else`, if any, and of the `\fi`.
+fn find_fi(tokens: &[Token], t: usize, declared: &[&str]) -> Option<(Option<usize>, usize)> {
+    let mut depth: usize = 0;
+    let mut els = None;
+    // the control word after `\newif` is not a conditional
+    let mut after_newif = false;
+    for (i, token) in tokens.iter().enumerate().skip(t) {
+        if !token.is(TokenKind::ControlWord) {
+            continue;
+        }
+        let name = token.name();
+        match name {
+            "fi" if depth == 0 => return Some((els, i)),
+            "fi" => depth -= 1,
+            "else" if depth == 0 && els.is_none() => els = Some(i),
+            _ if is_conditional(name, declared) && !after_newif => depth += 1,
+            _ => {}
+        }
+        after_newif = name == "newif";
+    }
+    None
+}
+
+/// Parses a color switch `\color{name}` or `\color[model]{spec}` at the beginning of a group,
+/// whose content starts at token `t`. The color must be one of `colors` unless it is empty.
+///
+/// Returns the index of the first token after the switch and its trailing whitespace.
+fn parse_color_switch(text: &str, tokens: &[Token], t: usize, colors: &[String]) -> Option<usize> {
+    let switch = skip_spaces(tokens, t);
+    tokens
+        .get(switch)
+        .filter(|token| token.is(TokenKind::ControlWord) && token.name() == "color")?;
+    let mut brace = skip_spaces(tokens, switch + 1);
+    if tokens.get(brace)?.is(TokenKind::OpenBracket) {
+        brace = parse_optional_args(tokens, brace)?.1;
+    }
+    let (color, close) = group_content(text, tokens, brace)?;
+    let color = color.trim();
+    if !(colors.is_empty() || colors.iter().any(|c| c == color)) {
+        return None;
+    }
+    Some(skip_spaces(tokens, close + 1))
+}
+
+/// Parses the optional arguments starting with `[` at token `t`.
+///
+/// Returns the bracket tokens of each optional argument and the token of the opening brace of the
+/// first mandatory argument, or `None` if no mandatory argument follows.
+fn parse_optional_args(tokens: &[Token], t: usize) -> Option<(Vec<(usize, usize)>, usize)> {
+    let mut optionals = vec![];
+    let mut t = t;
+    loop {
+        match tokens.get(t)?.kind {
+            TokenKind::OpenBracket => {
+                let open = t;
+                let mut brackets: usize = 0;
+                let mut braces: usize = 0;
+                let close = loop {
+                    t += 1;
+                    match tokens.get(t)?.kind {
+                        TokenKind::BeginGroup => braces += 1,
+                        TokenKind::EndGroup => braces = braces.checked_sub(1)?,
+                        TokenKind::OpenBracket if braces == 0 => brackets += 1,
+                        TokenKind::CloseBracket if braces == 0 => {
+                            if brackets == 0 {
+                                break t;
+                            }
+                            brackets -= 1;
+                        }
+                        _ => {}
+                    }
+                };
+                optionals.push((open, close));
+                t = skip_spaces(tokens, close + 1);
+            }
+            TokenKind::BeginGroup if !optionals.is_empty() => return Some((optionals, t)),
+            _ => return None,
+        }
+    }
+}
+
+/// Finds all markup in `text`, sorted by its start, keeping only markup within the 1-indexed
+/// lines `from` to `to`.
+fn find_occurrences(
+    text: &str,
+    markup: &Markup,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<Vec<Occurrence>, Errors> {
+    let verbatim: Vec<&str> = VERBATIM_ENVIRONMENTS
+        .into_iter()
+        .chain(markup.verbatim.iter().map(String::as_str))
+        .collect();
+    let tokens = tokenize_verbatim(text, &markup.verbatim);
+    let error = |kind: ErrorKind, span: Range<usize>| Errors::from(Error::new(kind, text, span));
+    let rules = &markup.rules;
+    let environments = &markup.environments;
+    let colors = &markup.colors;
+    // a group starting with a color switch is handled like a command with one argument
+    let color_group = Rule::new("color", Mode::Unwrap);
+    let conditionals = declared_conditionals(&tokens);
+    // index of the first token starting at or after byte `pos`
+    let token_at = |pos: usize| tokens.partition_point(|token| token.start < pos);
+    // line of the character at byte `pos`
+    let line_at = |pos: usize| {
+        let t = tokens.partition_point(|token| token.end <= pos);
+        tokens.get(t).map_or(0, |token| token.line)
+    };
+    // deletions of dead text, which is split into two pieces by a kept `\else` branch
+    let dead = |pieces: Vec<Span>| -> Vec<Deletion> {
+        let kinds = match pieces.len() {
+            1 => vec![Kind::Whole],
+            _ => vec![Kind::Opening, Kind::Closing],
+        };
+        pieces
+            .into_iter()
+            .zip(kinds)
+            .map(|((start, end), kind)| {
+                let (start, end) = whole_lines(text, start, end);
+                Deletion::range(start, end, line_at(start), kind)
+            })
+            .collect()
+    };
+
+    let mut open: Vec<OpenCommand> = vec![];
+    let mut open_environments: Vec<OpenEnvironment> = vec![];
+    // positions of the brackets which are not closed yet
+    let mut groups: Vec<usize> = vec![];
+    let mut occurrences: Vec<Occurrence> = vec![];
+    let mut t = 0;
+
+    while let Some(&token) = tokens.get(t) {
+        t += 1;
+        match token.kind {
+            TokenKind::ControlWord => match token.name() {
+                name @ ("begin" | "end") => {
+                    let Some((environment, close)) =
+                        group_content(text, &tokens, skip_spaces(&tokens, t))
+                    else {
+                        continue;
+                    };
+                    let environment = environment.trim();
+                    // last byte of `\begin{name}` or `\end{name}`
+                    let last = tokens[close].start;
+
+                    if let Some(e) = environments.iter().position(|e| e.name == environment) {
+                        t = close + 1;
+                        if name == "begin" {
+                            open_environments.push(OpenEnvironment {
+                                environment: e,
+                                depth: groups.len(),
+                                start: token.start,
+                                end: last,
+                                line: token.line,
+                            });
+                            continue;
+                        }
+
+                        let begin = match open_environments.pop() {
+                            Some(begin)
+                                if begin.environment == e && begin.depth == groups.len() =>
+                            {
+                                begin
+                            }
+                            other => {
+                                let kind = ErrorKind::UnmatchedEnd(environment.to_string());
+                                let mut error = Error::new(kind, text, token.start..last + 1);
+                                if let Some(other) = other {
+                                    let begin = other.start..other.end + 1;
+                                    let name = &environments[other.environment].name;
+                                    let note = if other.environment != e {
+                                        let message = format!(
+                                            "the last open environment is \\begin{{{name}}}"
+                                        );
+                                        Note::new(message, text, begin)
+                                    } else if let Some(&bracket) =
+                                        groups.get(other.depth..).and_then(<[usize]>::last)
+                                    {
+                                        let message = format!(
+                                            "this bracket is not closed before \\end{{{name}}}"
+                                        );
+                                        Note::new(message, text, bracket..bracket + 1)
+                                    } else {
+                                        let message = format!(
+                                            "\\begin{{{name}}} is inside a group which ends earlier"
+                                        );
+                                        Note::new(message, text, begin)
+                                    };
+                                    error = error.with_note(note);
+                                }
+                                return Err(error.into());
+                            }
+                        };
+                        let deletions = match environments[e].mode {
+                            Mode::Unwrap => {
+                                let (start, end) = whole_lines(text, begin.start, begin.end);
+                                let opening =
+                                    Deletion::range(start, end, begin.line, Kind::Opening);
+                                let (start, end) = whole_lines(text, token.start, last);
+                                let closing =
+                                    Deletion::range(start, end, token.line, Kind::Closing);
+                                vec![opening, closing]
+                            }
+                            Mode::Drop => {
+                                let (start, end) = whole_lines(text, begin.start, last);
+                                vec![Deletion::range(start, end, begin.line, Kind::Whole)]
+                            }
+                        };
+                        let name = environments[e].name.clone();
+                        let body = (begin.end + 1, token.start);
+                        occurrences.push(Occurrence::new(
+                            name,
+                            deletions,
+                            vec![body],
+                            begin.line,
+                            token.line,
+                        ));
+                    } else if name == "begin" && verbatim.contains(&environment) {
+                        let end_name = format!("\\end{{{environment}}}");
+                        let Some(offset) = text[last..].find(&end_name) else {
+                            let kind = ErrorKind::UnclosedEnvironment(environment.to_string());
+                            return Err(error(kind, token.start..last + 1));
+                        };
+                        let body = (last + 1, last + offset);
+                        let stop = body.1 + end_name.len();
+                        t = token_at(stop);
+                        if environment == "comment" && markup.strip_dead {
+                            let deletions = dead(vec![(token.start, stop - 1)]);
+                            let end_line = line_at(stop - 1);
+                            let name = environment.to_string();
+                            occurrences.push(Occurrence::new(
+                                name,
+                                deletions,
+                                vec![body],
+                                token.line,
+                                end_line,
+                            ));
+                        }
+                    }
+                }
+                "iffalse" if markup.strip_dead => {
+                    let Some((els, fi)) = find_fi(&tokens, t, &conditionals) else {
+                        return Err(error(
+                            ErrorKind::UnclosedConditional,
+                            token.start..token.end,
+                        ));
+                    };
+                    let fi = tokens[fi];
+                    let deletions = match els {
+                        // the else branch is kept
+                        Some(els) => dead(vec![
+                            (token.start, tokens[els].end - 1),
+                            (fi.start, fi.end - 1),
+                        ]),
+                        None => dead(vec![(token.start, fi.end - 1)]),
+                    };
+                    t = token_at(deletions[0].end + 1);
+                    let name = token.name().to_string();
+                    let body = (token.end, els.map_or(fi.start, |els| tokens[els].start));
+                    occurrences.push(Occurrence::new(
+                        name,
+                        deletions,
+                        vec![body],
+                        token.line,
+                        fi.line,
+                    ));
+                }
+                // the tokenizer only leaves `\verb` if it is not closed
+                "verb" => {
+                    return Err(error(ErrorKind::UnclosedVerb, token.start..token.end));
+                }
+                _ => {
+                    for rule in rules {
+                        let Some(after) = match_name(&tokens, t - 1, &rule.name) else {
+                            continue;
+                        };
+                        let mut brace = skip_spaces(&tokens, after);
+                        let mut kept = vec![];
+                        match tokens.get(brace).map(|next| next.kind) {
+                            Some(TokenKind::BeginGroup) => {}
+                            Some(TokenKind::OpenBracket) => {
+                                let (Some(mode), Some((optionals, first))) =
+                                    (rule.optional, parse_optional_args(&tokens, brace))
+                                else {
+                                    continue;
+                                };
+                                if mode == Mode::Unwrap {
+                                    kept.extend(optionals.iter().map(|&(open, close)| {
+                                        (tokens[open].end, tokens[close].start)
+                                    }));
+                                }
+                                brace = first;
+                            }
+                            _ => continue,
+                        }
+                        let arg_start = tokens[brace].end;
+                        if !rule.accepts(text, arg_start) {
+                            continue;
+                        }
+                        open.push(OpenCommand {
+                            rule,
+                            depth: groups.len(),
+                            start: token.start,
+                            line: token.line,
+                            arg: 0,
+                            arg_start,
+                            args: vec![],
+                            kept,
+                        });
+                        groups.push(tokens[brace].start);
+                        t = brace + 1;
+                        break;
+                    }
+                }
+            },
+            TokenKind::BeginGroup => {
+                let switch = colors
+                    .as_ref()
+                    .and_then(|colors| parse_color_switch(text, &tokens, t, colors));
+                if let Some(after) = switch {
+                    open.push(OpenCommand {
+                        rule: &color_group,
+                        depth: groups.len(),
+                        start: token.start,
+                        line: token.line,
+                        arg: 0,
+                        arg_start: tokens.get(after).map_or(text.len(), |next| next.start),
+                        args: vec![],
+                        kept: vec![],
+                    });
+                    t = after;
+                }
+                groups.push(token.start);
+            }
+            TokenKind::EndGroup => {
+                if groups.pop().is_none() {
+                    return Err(error(
+                        ErrorKind::UnmatchedClosingBrace,
+                        token.start..token.end,
+                    ));
+                }
+                let Some(command) = open
+                    .last_mut()
+                    .filter(|command| command.depth == groups.len())
+                else {
+                    continue;
+                };
+                let rule = command.rule;
+                command.args.push((command.arg_start, token.start));
+                if rule.args[command.arg] == Mode::Unwrap {
+                    command.kept.push((command.arg_start, token.start));
+                }
+                command.arg += 1;
+                if command.arg < rule.args.len() {
+                    // the next argument may only be separated by whitespace
+                    let brace = skip_spaces(&tokens, t);
+                    match tokens.get(brace) {
+                        Some(next) if next.is(TokenKind::BeginGroup) => {
+                            command.arg_start = next.end;
+                            groups.push(next.start);
+                            t = brace + 1;
+                        }
+                        _ => {
+                            let kind = ErrorKind::MissingArgument(rule.name.clone());
+                            return Err(error(kind, command.start..token.end));
+                        }
+                    }
+                } else {
+                    let command = open.pop().unwrap();
+                    occurrences.push(Occurrence::new(
+                        rule.name.clone(),
+                        command.deletions(text, token.start),
+                        command.args,
+                        command.line,
+                        token.line,
+                    ));
+                }
+            }
+            TokenKind::Comment if markup.strip_comments => {
+                if let Some(deletion) = comment_deletion(text, token.start, token.line) {
+                    let name = "%".to_string();
+                    occurrences.push(Occurrence::new(
+                        name,
+                        vec![deletion],
+                        vec![(token.start + 1, token.end)],
+                        token.line,
+                        token.line,
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // report every bracket, command and environment which is still open
+    let mut errors: Vec<Error> = groups
+        .iter()
+        .enumerate()
+        .map(
+            |(depth, &pos)| match open.iter().find(|c| c.depth == depth) {
+                Some(command) => {
+                    let kind = ErrorKind::UnclosedCommand(command.rule.name.clone());
+                    Error::new(kind, text, command.start..pos + 1)
+                }
+                None => Error::new(ErrorKind::UnclosedBrace, text, pos..pos + 1),
+            },
+        )
+        .collect();
+    errors.extend(open_environments.iter().map(|begin| {
+        let name = environments[begin.environment].name.clone();
+        Error::new(
+            ErrorKind::UnclosedEnvironment(name),
+            text,
+            begin.start..begin.end + 1,
+        )
+    }));
+    if !errors.is_empty() {
+        errors.sort_by_key(|error| error.location.offset);
+        return Err(Errors(errors));
+    }
+
+    occurrences.retain(|occurrence| {
+        let opening_line = occurrence.line + 1; // Convert to 1-indexed
+        let closing_line = occurrence.end_line + 1; // Convert to 1-indexed
+        let from_ok = from.is_none_or(|f| opening_line >= f && closing_line >= f);
+        let to_ok = to.is_none_or(|t| opening_line <= t && closing_line <= t);
+        from_ok && to_ok
+    });
+    occurrences.sort_by_key(|occurrence| occurrence.start());
+
+    Ok(occurrences)
+}
+
+fn find_deletions(
+    text: &str,
+    markup: &Markup,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<Vec<Deletion>, Errors> {
+    let mut deletions: Vec<Deletion> = find_occurrences(text, markup, from, to)?
+        .into_iter()
+        .flat_map(|occurrence| occurrence.deletions)
+        .collect();
+    deletions.sort();
+    Ok(deletions)
+}
+
+/// A file pulled in by another file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Include {
+    // `\input{file}` or `\include{file}`, relative to the main file
+    Input(String),
+    // `\subfile{file}`, relative to the including file
+    Subfile(String),
+    // `\import{dir}{file}`, where `dir` is relative to the main file
+    Import(String, String),
+    // `\subimport{dir}{file}`, where `dir` is relative to the including file
+    Subimport(String, String),
+}
+
+impl Include {
+    /// Resolves the path of the included file and the directory its own includes are relative to.
+    ///
+    /// `root` is the directory of the main file, `base` the directory includes of the including
+    /// file are relative to, and `dir` the directory of the including file.
+    fn resolve(&self, root: &Path, base: &Path, dir: &Path) -> (PathBuf, PathBuf) {
+        match self {
+            Include::Input(file) => (base.join(file), base.to_path_buf()),
+            Include::Subfile(file) => {
+                let path = dir.join(file);
+                let base = path.parent().unwrap_or(dir).to_path_buf();
+                (path, base)
+            }
+            Include::Import(import_dir, file) => {
+                let base = root.join(import_dir);
+                (base.join(file), base)
+            }
+            Include::Subimport(import_dir, file) => {
+                let base = base.join(import_dir);
+                (base.join(file), base)
+            }
+        }
+    }
+}
+
+/// Finds all includes outside of comments and verbatim text, together with their byte ranges.
+/// `verbatim` are further verbatim environments.
+fn find_includes(text: &str, verbatim: &[String]) -> Vec<(Include, Range<usize>)> {
+    let tokens = tokenize_verbatim(text, verbatim);
+    let mut includes = vec![];
+    for (t, token) in tokens.iter().enumerate() {
+        if !token.is(TokenKind::ControlWord) {
+            continue;
+        }
+        let num_args = match token.name() {
+            "input" | "include" | "subfile" => 1,
+            "import" | "subimport" => 2,
+            _ => continue,
+        };
+        let mut args = vec![];
+        let mut next = t + 1;
+        while args.len() < num_args {
+            let Some((arg, close)) = group_content(text, &tokens, skip_spaces(&tokens, next))
+            else {
+                break;
+            };
+            args.push(arg.trim().to_string());
+            next = close + 1;
+        }
+        let include = match (token.name(), args.as_slice()) {
+            ("input" | "include", [file]) => Include::Input(file.clone()),
+            ("subfile", [file]) => Include::Subfile(file.clone()),
+            ("import", [dir, file]) => Include::Import(dir.clone(), file.clone()),
+            ("subimport", [dir, file]) => Include::Subimport(dir.clone(), file.clone()),
+            _ => continue,
+        };
+        includes.push((include, token.start..tokens[next - 1].end));
+    }
+    includes
+}
+
+/// Returns the existing file LaTeX would read for `path`, trying the `.tex` extension first.
+fn tex_file(path: &Path) -> Option<PathBuf> {
+    let mut candidates = vec![];
+    if path.extension().is_none_or(|ext| ext != "tex") {
+        let mut with_extension = path.as_os_str().to_owned();
+        with_extension.push(".tex");
+        candidates.push(PathBuf::from(with_extension));
+    }
+    candidates.push(path.to_path_buf());
+    candidates.into_iter().find(|candidate| candidate.is_file())
+}
+
+/// Collects `root` and all files reachable from it via includes, in the order they appear,
+/// together with a warning for each included file which is not found. `verbatim` are further
+/// verbatim environments, whose content is not searched for includes.
+pub fn collect_files(root: &Path, verbatim: &[String]) -> Result<(Vec<PathBuf>, Vec<Warning>)> {
+    let root_dir = root.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut files = vec![];
+    let mut warnings = vec![];
+    let mut seen = HashSet::new();
+    // files still to visit, together with the directory their includes are relative to
+    let mut stack = vec![(root.to_path_buf(), root_dir.clone())];
+    while let Some((file, base)) = stack.pop() {
+        if !seen.insert(file.canonicalize()?) {
+            continue;
+        }
+        let text = std::fs::read_to_string(&file)
+            .with_context(|| format!("Failed to read {}", file.display()))?;
+        let dir = file.parent().unwrap_or(Path::new(""));
+        let mut children = vec![];
+        for (include, span) in find_includes(&text, verbatim) {
+            let (path, child_base) = include.resolve(&root_dir, &base, dir);
+            match tex_file(&path) {
+                Some(path) => children.push((path, child_base)),
+                None => {
+                    let message = format!("included file {} not found", path.display());
+                    warnings.push(Warning::new(message, &text, span).in_file(&file));
+                }
+            }
+        }
+        // visit children in document order
+        stack.extend(children.into_iter().rev());
+        files.push(file);
+    }
+    Ok((files, warnings))
+}
+
+/// Returns the deletions that are not contained in a previous one, e.g. commands inside a dropped command.
+fn outermost(deletions: &[Deletion]) -> Vec<&Deletion> {
+    let mut result: Vec<&Deletion> = vec![];
+    for del in deletions {
+        if result.last().is_none_or(|last| del.start > last.end) {
+            result.push(del);
+        }
+    }
+    result
+}
+
+fn end_of_line(text: &str, pos: usize) -> usize {
+    text[pos..].find('\n').map_or(text.len(), |p| pos + p)
+}
+
+/// Removes the deletions from `text` and returns the number of removed commands.
+fn clean_text(text: &mut String, deletions: Vec<Deletion>) -> usize {
+    let mut deleted: usize = 0;
+    let num = deletions
+        .iter()
+        .filter(|del| matches!(del.kind, Kind::Opening | Kind::Whole))
+        .count();
+    for del in outermost(&deletions) {
+        let start = del.start - deleted;
+        let end = del.end - deleted;
+        text.replace_range(start..=end, "");
+        deleted += del.len();
+    }
+
+    num
+}
+
+#[cfg(test)]
+mod test_clean {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::tokenizer::tokenize;
+
+    fn unwrap(commands: Vec<&str>) -> Vec<Rule> {
+        commands
+            .into_iter()
+            .map(|c| Rule::new(c, Mode::Unwrap))
+            .collect()
+    }
+
+    fn clean(text: &mut String, commands: Vec<&str>) -> Result<usize> {
+        let deletions = find_deletions(text, &unwrap(commands).into(), None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_no_clean() -> Result<()> {
+        let mut text1 = String::from("\\{  \\}");
+        let mut text2 = String::from("{  }");
+        clean(&mut text1, vec![])?;
+        clean(&mut text2, vec![])?;
+        assert_eq!(text1, "\\{  \\}");
+        assert_eq!(text2, "{  }");
+        Ok(())
+    }
+
+    #[test]
+    fn test_simple_clean() -> Result<()> {
+        let mut text = String::from("\\anew{ab}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "ab");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean() -> Result<()> {
+        let mut text = String::from("\\anew{ a{v}b \\% \\{ }");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, " a{v}b \\% \\{ ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_double_fake() -> Result<()> {
+        let mut text = String::from("\\ane\\anew{ a{v}b \\% \\{ }");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\ane a{v}b \\% \\{ ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_double() -> Result<()> {
+        let mut text = String::from("\\anew{\\anew{ a{v}b \\% \\{ }}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, " a{v}b \\% \\{ ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_2() -> Result<()> {
+        let mut text = String::from("\\anew{\\nnew{ a{v}b} \\{ }");
+        clean(&mut text, vec!["anew", "nnew"])?;
+        assert_eq!(text, " a{v}b \\{ ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_with_whitespace() -> Result<()> {
+        let mut text = String::from("\\anew   { { a{v}b} \\{ }");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, " { a{v}b} \\{ ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_with_whitespace_double() -> Result<()> {
+        let mut text = String::from("\\anew  \\anew  { { a{v}b} \\{ }");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\anew   { a{v}b} \\{ ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_with_whitespace_2() -> Result<()> {
+        let mut text = String::from("\\anew  f { { a{v}b} \\{ } \\anew { f}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\anew  f { { a{v}b} \\{ }  f");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_fails() -> Result<()> {
+        let mut text = String::from("{ } }");
+        assert!(clean(&mut text, vec!["anew"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_fails_2() -> Result<()> {
+        let mut text = String::from("\\anew{ } }");
+        assert!(clean(&mut text, vec!["anew"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_no_fail() -> Result<()> {
+        let mut text = String::from("\\}\\}\\}");
+        assert!(clean(&mut text, vec!["anew"]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_no_comment_newline() -> Result<()> {
+        let mut text = String::from("% % \\anew{ } \n \\anew{}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "% % \\anew{ } \n ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_no_newcommmand() -> Result<()> {
+        let mut text = String::from("\\newcommand{\\anew}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\newcommand{\\anew}");
+        Ok(())
+    }
+
+    // Tests for UTF-8 handling
+    #[test]
+    fn test_clean_with_unicode() -> Result<()> {
+        let mut text = String::from("\\anew{héllo wörld émoji 🎉}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "héllo wörld émoji 🎉");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_unicode_command_content() -> Result<()> {
+        let mut text = String::from("Préfix \\anew{中文内容} Süffix");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "Préfix 中文内容 Süffix");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_nested_unicode() -> Result<()> {
+        let mut text = String::from("\\alice{über \\bob{naïve} café}");
+        clean(&mut text, vec!["alice", "bob"])?;
+        assert_eq!(text, "über naïve café");
+        Ok(())
+    }
+
+    // Tests for line range filtering
+    fn clean_with_range(
+        text: &mut String,
+        commands: Vec<&str>,
+        from: Option<usize>,
+        to: Option<usize>,
+    ) -> Result<usize> {
+        let deletions = find_deletions(text, &unwrap(commands).into(), from, to)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_line_range_from() -> Result<()> {
+        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
+        clean_with_range(&mut text, vec!["anew"], Some(2), None)?;
+        assert_eq!(text, "\\anew{line1}\nline2\nline3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_to() -> Result<()> {
+        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
+        clean_with_range(&mut text, vec!["anew"], None, Some(2))?;
+        assert_eq!(text, "line1\nline2\n\\anew{line3}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_from_to() -> Result<()> {
+        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
+        clean_with_range(&mut text, vec!["anew"], Some(2), Some(2))?;
+        assert_eq!(text, "\\anew{line1}\nline2\n\\anew{line3}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_inclusive() -> Result<()> {
+        // Verify that --to is inclusive (line 2 should be cleaned)
+        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
+        clean_with_range(&mut text, vec!["anew"], Some(1), Some(2))?;
+        assert_eq!(text, "line1\nline2\n\\anew{line3}");
+        Ok(())
+    }
+
+    // Tests for Windows line endings
+    #[test]
+    fn test_clean_windows_line_endings() -> Result<()> {
+        let mut text = String::from("\\anew{line1}\r\n\\anew{line2}\r\n\\anew{line3}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "line1\r\nline2\r\nline3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_mixed_line_endings() -> Result<()> {
+        let mut text = String::from("\\anew{line1}\n\\anew{line2}\r\n\\anew{line3}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "line1\nline2\r\nline3");
+        Ok(())
+    }
+
+    // Tests for helper functions
+    #[test]
+    fn test_unmatched_bracket_error_with_unicode() {
+        let text = String::from("héllo } wörld");
+        let result = find_deletions(&text, &unwrap(vec!["anew"]).into(), None, None);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_unclosed_bracket_error() {
+        let text = String::from("\\anew{ unclosed");
+        let result = find_deletions(&text, &unwrap(vec!["anew"]).into(), None, None);
+        assert!(result.is_err());
+    }
+
+    // ============ Edge cases ============
+
+    #[test]
+    fn test_empty_file() -> Result<()> {
+        let mut text = String::from("");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_whitespace_only() -> Result<()> {
+        let mut text = String::from("   \n\t\n   ");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "   \n\t\n   ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_command_content() -> Result<()> {
+        let mut text = String::from("\\anew{}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_at_file_start() -> Result<()> {
+        let mut text = String::from("\\anew{start} middle end");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "start middle end");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_at_file_end() -> Result<()> {
+        let mut text = String::from("start middle \\anew{end}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "start middle end");
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_commands_same_line() -> Result<()> {
+        let mut text = String::from("\\alice{one} and \\bob{two} and \\alice{three}");
+        clean(&mut text, vec!["alice", "bob"])?;
+        assert_eq!(text, "one and two and three");
+        Ok(())
+    }
+
+    #[test]
+    fn test_deeply_nested_commands() -> Result<()> {
+        let mut text = String::from("\\a{\\b{\\c{\\d{deep}}}}");
+        clean(&mut text, vec!["a", "b", "c", "d"])?;
+        assert_eq!(text, "deep");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_spanning_lines() -> Result<()> {
+        let mut text = String::from("\\anew{line1\nline2\nline3}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "line1\nline2\nline3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_commands() -> Result<()> {
+        let mut text = String::from("\\anew{\\bob{\\charlie{x}}}");
+        clean(&mut text, vec!["anew", "bob", "charlie"])?;
+        assert_eq!(text, "x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjacent_commands() -> Result<()> {
+        let mut text = String::from("\\a{x}\\b{y}\\c{z}");
+        clean(&mut text, vec!["a", "b", "c"])?;
+        assert_eq!(text, "xyz");
+        Ok(())
+    }
+
+    // ============ Whitespace handling ============
+
+    #[test]
+    fn test_tab_before_brace() -> Result<()> {
+        let mut text = String::from("\\anew\t{content}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "content");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mixed_whitespace_before_brace() -> Result<()> {
+        let mut text = String::from("\\anew \t  {content}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "content");
+        Ok(())
+    }
+
+    #[test]
+    fn test_newline_before_brace_no_match() -> Result<()> {
+        // Newline between command and brace should NOT be treated as the command
+        let mut text = String::from("\\anew\n{content}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\anew\n{content}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_with_leading_trailing_whitespace() -> Result<()> {
+        let mut text = String::from("\\anew{  spaced  }");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "  spaced  ");
+        Ok(())
+    }
+
+    // ============ LaTeX-specific patterns ============
+
+    #[test]
+    fn test_command_in_math_mode() -> Result<()> {
+        let mut text = String::from("$x = \\anew{y + z}$");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "$x = y + z$");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_in_display_math() -> Result<()> {
+        let mut text = String::from("\\[\n\\anew{A = B}\n\\]");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\[\nA = B\n\\]");
+        Ok(())
+    }
+
+    #[test]
+    fn test_similar_command_prefixes() -> Result<()> {
+        // Should only match exact command name
+        let mut text = String::from("\\anew{x} \\anewer{y} \\an{z}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "x \\anewer{y} \\an{z}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_with_numbers() -> Result<()> {
+        let mut text = String::from("\\rev1{first} \\rev2{second}");
+        clean(&mut text, vec!["rev1", "rev2"])?;
+        assert_eq!(text, "first second");
+        Ok(())
+    }
+
+    #[test]
+    fn test_escaped_braces_in_content() -> Result<()> {
+        let mut text = String::from("\\anew{set \\{ a, b \\}}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "set \\{ a, b \\}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_regular_braces() -> Result<()> {
+        let mut text = String::from("\\anew{outer {inner {deep}} end}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "outer {inner {deep}} end");
+        Ok(())
+    }
+
+    #[test]
+    fn test_latex_environments_preserved() -> Result<()> {
+        let mut text = String::from("\\begin{equation}\n\\anew{x = y}\n\\end{equation}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\begin{equation}\nx = y\n\\end{equation}");
+        Ok(())
+    }
+
+    // ============ Comment handling ============
+
+    #[test]
+    fn test_comment_at_end_of_line() -> Result<()> {
+        let mut text = String::from("\\anew{visible} % \\anew{commented}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "visible % \\anew{commented}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_comment_lines() -> Result<()> {
+        let mut text = String::from("% \\anew{a}\n% \\anew{b}\n\\anew{c}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "% \\anew{a}\n% \\anew{b}\nc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_escaped_percent_not_comment() -> Result<()> {
+        let mut text = String::from("\\anew{100\\% done}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "100\\% done");
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment_then_code_same_line_next() -> Result<()> {
+        let mut text = String::from("% comment\n\\anew{code}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "% comment\ncode");
+        Ok(())
+    }
+
+    // ============ Line range edge cases ============
+
+    #[test]
+    fn test_line_range_first_line_only() -> Result<()> {
+        let mut text = String::from("\\anew{first}\n\\anew{second}");
+        clean_with_range(&mut text, vec!["anew"], Some(1), Some(1))?;
+        assert_eq!(text, "first\n\\anew{second}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_last_line_only() -> Result<()> {
+        let mut text = String::from("\\anew{first}\n\\anew{second}");
+        clean_with_range(&mut text, vec!["anew"], Some(2), Some(2))?;
+        assert_eq!(text, "\\anew{first}\nsecond");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_spanning_command_excluded() -> Result<()> {
+        // Command spans lines 1-3, but range is only line 2
+        // Command should NOT be removed since it's not completely inside range
+        let mut text = String::from("\\anew{line1\nline2\nline3}");
+        clean_with_range(&mut text, vec!["anew"], Some(2), Some(2))?;
+        assert_eq!(text, "\\anew{line1\nline2\nline3}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_spanning_command_included() -> Result<()> {
+        // Command spans lines 1-3, range includes all
+        let mut text = String::from("\\anew{line1\nline2\nline3}");
+        clean_with_range(&mut text, vec!["anew"], Some(1), Some(3))?;
+        assert_eq!(text, "line1\nline2\nline3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_no_commands_in_range() -> Result<()> {
+        let mut text = String::from("plain\n\\anew{marked}\nplain");
+        clean_with_range(&mut text, vec!["anew"], Some(1), Some(1))?;
+        assert_eq!(text, "plain\n\\anew{marked}\nplain");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_range_from_zero_treated_as_start() -> Result<()> {
+        // from=0 should work (though 1-indexed, 0 means "from start")
+        let mut text = String::from("\\anew{first}\n\\anew{second}");
+        clean_with_range(&mut text, vec!["anew"], Some(0), Some(1))?;
+        assert_eq!(text, "first\n\\anew{second}");
+        Ok(())
+    }
+
+    // ============ Multiple/complex commands ============
+
+    #[test]
+    fn test_three_different_commands() -> Result<()> {
+        let mut text = String::from("\\alice{A} \\bob{B} \\charlie{C}");
+        clean(&mut text, vec!["alice", "bob", "charlie"])?;
+        assert_eq!(text, "A B C");
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_command_repeated() -> Result<()> {
+        let mut text = String::from("\\x{1}\\x{2}\\x{3}\\x{4}\\x{5}");
+        clean(&mut text, vec!["x"])?;
+        assert_eq!(text, "12345");
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleaved_nested_commands() -> Result<()> {
+        let mut text = String::from("\\a{1\\b{2}3}\\b{4\\a{5}6}");
+        clean(&mut text, vec!["a", "b"])?;
+        assert_eq!(text, "123456");
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_command_match() -> Result<()> {
+        // "new" should not match "\anew"
+        let mut text = String::from("\\anew{content}");
+        clean(&mut text, vec!["new"])?;
+        assert_eq!(text, "\\anew{content}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_not_in_list() -> Result<()> {
+        let mut text = String::from("\\alice{A} \\bob{B}");
+        clean(&mut text, vec!["charlie"])?;
+        assert_eq!(text, "\\alice{A} \\bob{B}");
+        Ok(())
+    }
+
+    // ============ Error handling ============
+
+    #[test]
+    fn test_multiple_unmatched_closing() {
+        let text = String::from("text } more } end");
+        let result = find_deletions(&text, &unwrap(vec!["anew"]).into(), None, None);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_multiple_unmatched_opening() {
+        let text = String::from("\\anew{ \\bob{ content }");
+        let result = find_deletions(&text, &unwrap(vec!["anew", "bob"]).into(), None, None);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_mismatched_in_nested() {
+        let text = String::from("\\anew{outer \\bob{inner}");
+        let result = find_deletions(&text, &unwrap(vec!["anew", "bob"]).into(), None, None);
+        assert!(result.is_err());
+    }
+
+    // ============ Real-world LaTeX patterns ============
+
+    #[test]
+    fn test_citation_inside_command() -> Result<()> {
+        let mut text = String::from("\\anew{as shown in~\\cite{smith2020}}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "as shown in~\\cite{smith2020}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_footnote_inside_command() -> Result<()> {
+        let mut text = String::from("\\anew{text\\footnote{A footnote.}}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "text\\footnote{A footnote.}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_textbf_inside_command() -> Result<()> {
+        let mut text = String::from("\\anew{\\textbf{bold} and normal}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(text, "\\textbf{bold} and normal");
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiline_paragraph() -> Result<()> {
+        let mut text = String::from(
+            "\\anew{This is a long paragraph\nthat spans multiple lines\nand has various content.}",
+        );
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(
+            text,
+            "This is a long paragraph\nthat spans multiple lines\nand has various content."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_environment() -> Result<()> {
+        let mut text =
+            String::from("\\begin{tabular}{cc}\n\\anew{A} & B \\\\\nC & \\anew{D}\n\\end{tabular}");
+        clean(&mut text, vec!["anew"])?;
+        assert_eq!(
+            text,
+            "\\begin{tabular}{cc}\nA & B \\\\\nC & D\n\\end{tabular}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_itemize_environment() -> Result<()> {
+        let mut text = String::from(
+            "\\begin{itemize}\n\\item \\anew{First}\n\\item \\bob{Second}\n\\end{itemize}",
+        );
+        clean(&mut text, vec!["anew", "bob"])?;
+        assert_eq!(
+            text,
+            "\\begin{itemize}\n\\item First\n\\item Second\n\\end{itemize}"
+        );
+        Ok(())
+    }
+
+    // ============ Drop mode ============
+
+    fn clean_modes(text: &mut String, commands: Vec<(&str, Mode)>) -> Result<usize> {
+        let rules: Vec<Rule> = commands
+            .into_iter()
+            .map(|(c, mode)| Rule::new(c, mode))
+            .collect();
+        let deletions = find_deletions(text, &rules.into(), None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_drop_simple() -> Result<()> {
+        let mut text = String::from("before \\todo{fix this} after");
+        clean_modes(&mut text, vec![("todo", Mode::Drop)])?;
+        assert_eq!(text, "before  after");
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_and_unwrap() -> Result<()> {
+        let mut text = String::from("\\alice{kept} \\todo{{nested} gone} \\alice{also}");
+        let num = clean_modes(
+            &mut text,
+            vec![("todo", Mode::Drop), ("alice", Mode::Unwrap)],
+        )?;
+        assert_eq!(text, "kept  also");
+        assert_eq!(num, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_with_nested_unwrap() -> Result<()> {
+        let mut text = String::from("a\\todo{x \\alice{y} z}b \\alice{c}");
+        clean_modes(
+            &mut text,
+            vec![("todo", Mode::Drop), ("alice", Mode::Unwrap)],
+        )?;
+        assert_eq!(text, "ab c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unwrap_with_nested_drop() -> Result<()> {
+        let mut text = String::from("\\alice{x \\todo{y} z}");
+        clean_modes(
+            &mut text,
+            vec![("todo", Mode::Drop), ("alice", Mode::Unwrap)],
+        )?;
+        assert_eq!(text, "x  z");
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_nested_drop() -> Result<()> {
+        let mut text = String::from("a\\todo{\\todo{inner} outer}b");
+        clean_modes(&mut text, vec![("todo", Mode::Drop)])?;
+        assert_eq!(text, "ab");
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_spanning_lines() -> Result<()> {
+        let mut text = String::from("line1\n\\todo{a\nb}\nline3");
+        clean_modes(&mut text, vec![("todo", Mode::Drop)])?;
+        assert_eq!(text, "line1\n\nline3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_unbalanced_fails() {
+        let text = String::from("\\todo{ unclosed");
+        let result = find_deletions(
+            &text,
+            &vec![Rule::new("todo", Mode::Drop)].into(),
+            None,
+            None,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_drop_line_range() -> Result<()> {
+        let mut text = String::from("\\todo{a}\n\\todo{b\nc}");
+        let deletions = find_deletions(
+            &text,
+            &vec![Rule::new("todo", Mode::Drop)].into(),
+            Some(2),
+            Some(2),
+        )?;
+        clean_text(&mut text, deletions);
+        assert_eq!(text, "\\todo{a}\n\\todo{b\nc}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_outermost_skips_nested() {
+        let deletions = vec![
+            Deletion::range(0, 10, 0, Kind::Whole),
+            Deletion::range(2, 4, 0, Kind::Opening),
+            Deletion::range(9, 9, 0, Kind::Closing),
+            Deletion::range(12, 13, 0, Kind::Opening),
+        ];
+        let outer: Vec<usize> = outermost(&deletions).iter().map(|d| d.start).collect();
+        assert_eq!(outer, vec![0, 12]);
+    }
+
+    // ============ Changes package ============
+
+    fn accept(text: &mut String) -> Result<usize> {
+        let deletions = find_deletions(text, &accept_changes().into(), None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_accept_added() -> Result<()> {
+        let mut text = String::from("a \\added[id=A]{new text} b \\added{more}");
+        accept(&mut text)?;
+        assert_eq!(text, "a new text b more");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_deleted() -> Result<()> {
+        let mut text = String::from("a \\deleted[id=B, comment={x}]{old text} b");
+        accept(&mut text)?;
+        assert_eq!(text, "a  b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_replaced() -> Result<()> {
+        let mut text = String::from("a \\replaced[id=A]{new}{old} b \\replaced{x} {y}");
+        let num = accept(&mut text)?;
+        assert_eq!(text, "a new b x");
+        assert_eq!(num, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_nested() -> Result<()> {
+        let mut text = String::from("\\replaced{a \\added{b}}{c \\deleted{d}}");
+        accept(&mut text)?;
+        assert_eq!(text, "a b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_replaced_spanning_lines() -> Result<()> {
+        let mut text = String::from("\\replaced{new\nlines}{old\nlines}\n\\added{x}");
+        accept(&mut text)?;
+        assert_eq!(text, "new\nlines\nx");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_nested_optional_brackets() -> Result<()> {
+        let mut text = String::from("\\added[comment={[a]}, id=[b]]{x}");
+        accept(&mut text)?;
+        assert_eq!(text, "x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_optional_without_argument() -> Result<()> {
+        let mut text = String::from("\\added[id=A] text");
+        accept(&mut text)?;
+        assert_eq!(text, "\\added[id=A] text");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_missing_argument_fails() {
+        let text = String::from("\\replaced{new} text");
+        assert!(find_deletions(&text, &accept_changes().into(), None, None).is_err());
+    }
+
+    #[test]
+    fn test_optional_args_removed() -> Result<()> {
+        let mut text = String::from("\\alice[comment]{text} \\todo[inline, color={red}]{note}");
+        clean_modes(
+            &mut text,
+            vec![("alice", Mode::Unwrap), ("todo", Mode::Drop)],
+        )?;
+        assert_eq!(text, "text ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_args_kept() -> Result<()> {
+        let mut text = String::from("\\alice[see [1]] [x]{text}");
+        let rules = vec![Rule {
+            optional: Some(Mode::Unwrap),
+            ..Rule::new("alice", Mode::Unwrap)
+        }];
+        let deletions = find_deletions(&text, &rules.into(), None, None)?;
+        let num = clean_text(&mut text, deletions);
+        assert_eq!(text, "see [1]xtext");
+        assert_eq!(num, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_args_with_whitespace() -> Result<()> {
+        let mut text = String::from("\\alice [a] {b}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_args_nested_commands() -> Result<()> {
+        let mut text = String::from("\\alice[\\bob{x}]{\\bob[y]{z}}");
+        clean(&mut text, vec!["alice", "bob"])?;
+        assert_eq!(text, "z");
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_args_without_argument() -> Result<()> {
+        let mut text = String::from("\\alice[x] y \\alice[z]\n{w}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "\\alice[x] y \\alice[z]\n{w}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_optional_args() {
+        let optional_args = |text: &str, t: usize| parse_optional_args(&tokenize(text), t);
+        assert_eq!(optional_args("[a]{", 0), Some((vec![(0, 2)], 3)));
+        assert_eq!(
+            optional_args("x[a] [{]}]\t{", 1),
+            Some((vec![(1, 3), (5, 9)], 11))
+        );
+        assert_eq!(optional_args("[a]\n{", 0), None);
+        assert_eq!(optional_args("[a", 0), None);
+        assert_eq!(optional_args("[a] b", 0), None);
+        assert_eq!(optional_args("[\\]]{", 0), Some((vec![(0, 2)], 3)));
+    }
+
+    fn reject(text: &mut String) -> Result<usize> {
+        let deletions = find_deletions(text, &reject_changes().into(), None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_reject_changes() -> Result<()> {
+        let mut text = String::from(
+            "a \\added[id=A]{new} b \\deleted[id=B]{old} c \\replaced[id=A]{new}{old}",
+        );
+        let num = reject(&mut text)?;
+        assert_eq!(text, "a  b old c old");
+        assert_eq!(num, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_nested() -> Result<()> {
+        let mut text = String::from("\\replaced{a \\added{b}}{c \\deleted{d} \\added{e}}");
+        reject(&mut text)?;
+        assert_eq!(text, "c d ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_reject_inverse() -> Result<()> {
+        let original = "x \\replaced{1}{2} \\added{3}\\deleted{4}";
+        let mut accepted = String::from(original);
+        let mut rejected = String::from(original);
+        accept(&mut accepted)?;
+        reject(&mut rejected)?;
+        assert_eq!(accepted, "x 1 3");
+        assert_eq!(rejected, "x 2 4");
+        Ok(())
+    }
+
+    // ============ Custom rules ============
+
+    #[test]
+    fn test_parse_rule() -> Result<()> {
+        let rule: Rule = "textcolor:drop,keep".parse()?;
+        assert_eq!(rule.name, "textcolor");
+        assert_eq!(rule.args, vec![Mode::Drop, Mode::Unwrap]);
+        let rule: Rule = "\\comment: drop , drop".parse()?;
+        assert_eq!(rule.name, "comment");
+        assert_eq!(rule.args, vec![Mode::Drop, Mode::Drop]);
+        assert!("textcolor".parse::<Rule>().is_err());
+        assert!(":keep".parse::<Rule>().is_err());
+        assert!("textcolor:keep,".parse::<Rule>().is_err());
+        assert!("textcolor:unwrap".parse::<Rule>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rule_textcolor() -> Result<()> {
+        let mut text = String::from("a \\textcolor{red}{important} b");
+        let deletions = find_deletions(
+            &text,
+            &vec!["textcolor:drop,keep".parse()?].into(),
+            None,
+            None,
+        )?;
+        clean_text(&mut text, deletions);
+        assert_eq!(text, "a important b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rule_comment() -> Result<()> {
+        let mut text = String::from("a\\comment{alice}{Is this {right}?} b \\alice{c}");
+        let rules = vec![
+            "comment:drop,drop".parse()?,
+            Rule::new("alice", Mode::Unwrap),
+        ];
+        let deletions = find_deletions(&text, &rules.into(), None, None)?;
+        let num = clean_text(&mut text, deletions);
+        assert_eq!(text, "a b c");
+        assert_eq!(num, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rule_three_arguments() -> Result<()> {
+        let mut text = String::from("\\three{a}{b} {c}");
+        let deletions = find_deletions(
+            &text,
+            &vec!["three:keep,drop,keep".parse()?].into(),
+            None,
+            None,
+        )?;
+        clean_text(&mut text, deletions);
+        assert_eq!(text, "ac");
+        Ok(())
+    }
+
+    // ============ Environments ============
+
+    fn clean_environments(text: &mut String, environments: Vec<(&str, Mode)>) -> Result<usize> {
+        let markup = Markup {
+            environments: environments
+                .into_iter()
+                .map(|(name, mode)| Environment::new(name, mode))
+                .collect(),
+            ..Default::default()
+        };
+        let deletions = find_deletions(text, &markup, None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_environment_unwrap() -> Result<()> {
+        let mut text = String::from("a\n\\begin{alice}\nnew text\n\\end{alice}\nb");
+        let num = clean_environments(&mut text, vec![("alice", Mode::Unwrap)])?;
+        assert_eq!(text, "a\nnew text\nb");
+        assert_eq!(num, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_inline() -> Result<()> {
+        let mut text = String::from("a \\begin{alice}new\\end{alice} b");
+        clean_environments(&mut text, vec![("alice", Mode::Unwrap)])?;
+        assert_eq!(text, "a new b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_drop() -> Result<()> {
+        let mut text =
+            String::from("a\n  \\begin{note}\n{x}\n\\end{note}  \nb \\begin{note}y\\end{note}");
+        clean_environments(&mut text, vec![("note", Mode::Drop)])?;
+        assert_eq!(text, "a\nb ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_nested() -> Result<()> {
+        let mut text = String::from(
+            "\\begin{alice}x\\begin{bob}y\\begin{alice}z\\end{alice}\\end{bob}\\end{alice}",
+        );
+        clean_environments(
+            &mut text,
+            vec![("alice", Mode::Unwrap), ("bob", Mode::Drop)],
+        )?;
+        assert_eq!(text, "x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_with_commands() -> Result<()> {
+        let mut text = String::from("\\begin{alice}\\bob{x} {y}\\end{alice}");
+        let markup = Markup {
+            rules: vec![Rule::new("bob", Mode::Unwrap)],
+            environments: vec![Environment::new("alice", Mode::Unwrap)],
+            ..Default::default()
+        };
+        let deletions = find_deletions(&text, &markup, None, None)?;
+        clean_text(&mut text, deletions);
+        assert_eq!(text, "x {y}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_other_preserved() -> Result<()> {
+        let mut text =
+            String::from("\\begin{alicex}a\\end{alicex}\\begin{equation}b\\end{equation}");
+        clean_environments(&mut text, vec![("alice", Mode::Unwrap)])?;
+        assert_eq!(
+            text,
+            "\\begin{alicex}a\\end{alicex}\\begin{equation}b\\end{equation}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_commented() -> Result<()> {
+        let mut text = String::from("% \\begin{alice}\n\\begin{alice}a\\end{alice} % \\end{alice}");
+        clean_environments(&mut text, vec![("alice", Mode::Unwrap)])?;
+        assert_eq!(text, "% \\begin{alice}\na % \\end{alice}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_mismatch_fails() {
+        let markup = Markup {
+            rules: vec![Rule::new("bob", Mode::Unwrap)],
+            environments: vec![
+                Environment::new("alice", Mode::Unwrap),
+                Environment::new("carol", Mode::Unwrap),
+            ],
+            ..Default::default()
+        };
+        for text in [
+            "\\begin{alice}",
+            "\\end{alice}",
+            "\\begin{alice}\\begin{carol}\\end{alice}\\end{carol}",
+            "\\bob{\\begin{alice}}\\end{alice}",
+            "\\begin{alice}{\\end{alice}}",
+        ] {
+            assert!(find_deletions(text, &markup, None, None).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_environment_line_range() -> Result<()> {
+        let mut text = String::from("\\begin{alice}\na\n\\end{alice}\n\\begin{alice}b\\end{alice}");
+        let markup = Markup {
+            environments: vec![Environment::new("alice", Mode::Unwrap)],
+            ..Default::default()
+        };
+        let deletions = find_deletions(&text, &markup, Some(4), None)?;
+        clean_text(&mut text, deletions);
+        assert_eq!(text, "\\begin{alice}\na\n\\end{alice}\nb");
+        Ok(())
+    }
+
+    #[test]
+    fn test_whole_lines() {
+        let text = "a\n  \\x  \r\nb \\y\n\\z";
+        assert_eq!(whole_lines(text, 4, 5), (2, 9));
+        assert_eq!(whole_lines(text, 12, 13), (12, 13));
+        assert_eq!(whole_lines(text, 15, 16), (15, 16));
+    }
+
+    // ============ Colors ============
+
+    fn clean_colors(text: &mut String, colors: Vec<&str>) -> Result<usize> {
+        let colors: Vec<String> = colors.into_iter().map(String::from).collect();
+        let markup = Markup {
+            rules: vec![Rule::textcolor(&colors)],
+            colors: Some(colors),
+            ..Default::default()
+        };
+        let deletions = find_deletions(text, &markup, None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_color_group() -> Result<()> {
+        let mut text = String::from("a {\\color{blue} new {text}} b {\\color {red}x}");
+        let num = clean_colors(&mut text, vec![])?;
+        assert_eq!(text, "a new {text} b x");
+        assert_eq!(num, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_group_restricted() -> Result<()> {
+        let mut text = String::from("{\\color{blue}a} {\\color{red}b} {\\color[rgb]{0,0,1}c}");
+        clean_colors(&mut text, vec!["blue", "0,0,1"])?;
+        assert_eq!(text, "a {\\color{red}b} c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_group_not_first() -> Result<()> {
+        let mut text = String::from("{a \\color{blue} b} {\\colorbox{blue}{c}} {\\color}");
+        clean_colors(&mut text, vec![])?;
+        assert_eq!(text, "{a \\color{blue} b} {\\colorbox{blue}{c}} {\\color}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_group_spanning_lines() -> Result<()> {
+        let mut text = String::from("{\\color{blue}\n a\n b}");
+        clean_colors(&mut text, vec![])?;
+        assert_eq!(text, "\n a\n b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_textcolor() -> Result<()> {
+        let mut text = String::from("\\textcolor{blue}{a} \\textcolor{red}{b {c}}");
+        clean_colors(&mut text, vec![])?;
+        assert_eq!(text, "a b {c}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_textcolor_restricted() -> Result<()> {
+        let mut text = String::from("\\textcolor{ blue }{a} \\textcolor{red}{b} \\textcolor{x}");
+        clean_colors(&mut text, vec!["blue"])?;
+        assert_eq!(text, "a \\textcolor{red}{b} \\textcolor{x}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_colors_nested() -> Result<()> {
+        let mut text = String::from("{\\color{blue} a \\textcolor{red}{b {\\color{red}c}}}");
+        clean_colors(&mut text, vec!["blue", "red"])?;
+        assert_eq!(text, "a b c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_colors_unbalanced_fails() {
+        let markup = Markup {
+            colors: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(find_deletions("{\\color{blue} a", &markup, None, None).is_err());
+    }
+
+    // ============ Dead text ============
+
+    fn strip_dead(text: &mut String) -> Result<usize> {
+        let markup = Markup {
+            rules: vec![Rule::new("alice", Mode::Unwrap)],
+            strip_dead: true,
+            ..Default::default()
+        };
+        let deletions = find_deletions(text, &markup, None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_strip_iffalse() -> Result<()> {
+        let mut text = String::from("a\n\\iffalse\nold { text\n\\fi\nb \\iffalse x\\fi c");
+        let num = strip_dead(&mut text)?;
+        assert_eq!(text, "a\nb  c");
+        assert_eq!(num, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_iffalse_nested() -> Result<()> {
+        let mut text = String::from(
+            "\\iffalse \\ifx\\a\\b x\\else y\\fi \\newif\\ifdraft \\ifthenelse{}{}{} % \\fi\n\\fi z",
+        );
+        strip_dead(&mut text)?;
+        assert_eq!(text, " z");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_iffalse_else() -> Result<()> {
+        let mut text = String::from("\\iffalse\nold\n\\else\n\\alice{new}\n\\fi\nend");
+        strip_dead(&mut text)?;
+        assert_eq!(text, "new\nend");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_iffalse_other_commands() -> Result<()> {
+        let mut text = String::from("\\iffalsey x \\iftrue y\\fi % \\iffalse");
+        strip_dead(&mut text)?;
+        assert_eq!(text, "\\iffalsey x \\iftrue y\\fi % \\iffalse");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_iffalse_unclosed_fails() {
+        let markup = Markup {
+            strip_dead: true,
+            ..Default::default()
+        };
+        for text in ["\\iffalse x", "\\iffalse \\ifx x \\fi", "\\iffalse % \\fi"] {
+            assert!(find_deletions(text, &markup, None, None).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_strip_comment_environment() -> Result<()> {
+        let mut text = String::from(
+            "a\n\\begin{comment}\n} unbalanced \\alice{\n\\end{comment}\n\\alice{b}\\begin{comment}c\\end{comment}",
+        );
+        let num = strip_dead(&mut text)?;
+        assert_eq!(text, "a\nb");
+        assert_eq!(num, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comment_environment_unclosed_fails() {
+        let markup = Markup {
+            strip_dead: true,
+            ..Default::default()
+        };
+        assert!(find_deletions("\\begin{comment} x", &markup, None, None).is_err());
+    }
+
+    #[test]
+    fn test_strip_dead_disabled() -> Result<()> {
+        let mut text = String::from("\\iffalse x \\fi \\begin{comment}y\\end{comment}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "\\iffalse x \\fi \\begin{comment}y\\end{comment}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_fi() {
+        let fi = |text: &str| find_fi(&tokenize(text), 0, &[]);
+        assert_eq!(fi(" a \\fi"), Some((None, 3)));
+        assert_eq!(fi("\\else\\fi"), Some((Some(0), 1)));
+        assert_eq!(fi("\\if\\fi \\% \\fi"), Some((None, 5)));
+        assert_eq!(fi("\\if\\fi %\\fi\n\\fi"), Some((None, 5)));
+        assert_eq!(fi("\\fil"), None);
+        assert_eq!(fi("\\iff\\ifthenelse \\fi"), Some((None, 3)));
+        assert_eq!(fi("\\ifdraft\\fi \\fi"), Some((None, 3)));
+        assert_eq!(
+            fi("\\ifdefempty\\ifstrequal\\ifnumodd \\fi"),
+            Some((None, 4))
+        );
+        assert_eq!(fi("\\ifdefined\\fi \\ifnum\\fi \\fi"), Some((None, 6)));
+        let tokens = tokenize("\\ifstrict\\fi \\fi");
+        assert_eq!(find_fi(&tokens, 0, &["ifstrict"]), Some((None, 3)));
+    }
+
+    #[test]
+    fn test_strip_iffalse_iff() -> Result<()> {
+        let mut text = String::from("\\iffalse\n$a \\iff b$\n\\fi\nkeep");
+        strip_dead(&mut text)?;
+        assert_eq!(text, "keep");
+
+        let mut text = String::from("a\n\\iffalse\n$a \\iff b$\n\\fi\nb \\fi");
+        strip_dead(&mut text)?;
+        assert_eq!(text, "a\nb \\fi");
+
+        let mut text =
+            String::from("\\iffalse\n\\ifpdf old pdf\\else old dvi\\fi\nold text\n\\fi\nkeep");
+        strip_dead(&mut text)?;
+        assert_eq!(text, "keep");
+
+        let mut text = String::from("\\newif\\ifdraft\n\\iffalse \\ifdraft x\\fi y\\fi z");
+        strip_dead(&mut text)?;
+        assert_eq!(text, "\\newif\\ifdraft\n z");
+        Ok(())
+    }
+
+    // ============ Comments ============
+
+    fn strip_comments(text: &mut String) -> Result<usize> {
+        let markup = Markup {
+            rules: vec![Rule::new("alice", Mode::Unwrap)],
+            strip_comments: true,
+            ..Default::default()
+        };
+        let deletions = find_deletions(text, &markup, None, None)?;
+        Ok(clean_text(text, deletions))
+    }
+
+    #[test]
+    fn test_strip_full_line_comments() -> Result<()> {
+        let mut text = String::from("% header\na\n  % indented \\alice{x}\nb\n%");
+        strip_comments(&mut text)?;
+        assert_eq!(text, "a\nb\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_trailing_comments() -> Result<()> {
+        let mut text = String::from("a % note\nb\t%note\n\\alice{c} % \\alice{d}");
+        strip_comments(&mut text)?;
+        assert_eq!(text, "a\nb\nc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comments_keeps_percent_after_text() -> Result<()> {
+        let mut text = String::from("\\newcommand{\\x}{%\n  y% note\n}%");
+        strip_comments(&mut text)?;
+        assert_eq!(text, "\\newcommand{\\x}{%\n  y%\n}%");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comments_escaped_percent() -> Result<()> {
+        let mut text = String::from("100\\% sure % really?\n50\\%");
+        strip_comments(&mut text)?;
+        assert_eq!(text, "100\\% sure\n50\\%");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comments_non_ascii() -> Result<()> {
+        let mut text = String::from("Texte % à revoir: café\nsuite\n");
+        strip_comments(&mut text)?;
+        assert_eq!(text, "Texte\nsuite\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comments_percent_in_comment() -> Result<()> {
+        let mut text = String::from("a % b % c\n");
+        strip_comments(&mut text)?;
+        assert_eq!(text, "a\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comments_windows_line_endings() -> Result<()> {
+        let mut text = String::from("% x\r\na % y\r\nb%z\r\n");
+        strip_comments(&mut text)?;
+        assert_eq!(text, "a\r\nb%\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comments_verbatim() -> Result<()> {
+        let mut text = String::from(
+            "\\begin{verbatim}\n% kept { \n\\end{verbatim}\n\\begin{lstlisting}[language=TeX]\nx % kept\n\\end{lstlisting} % removed",
+        );
+        strip_comments(&mut text)?;
+        assert_eq!(
+            text,
+            "\\begin{verbatim}\n% kept { \n\\end{verbatim}\n\\begin{lstlisting}[language=TeX]\nx % kept\n\\end{lstlisting}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_comments_unclosed_verbatim_fails() {
+        let markup = Markup {
+            strip_comments: true,
+            ..Default::default()
+        };
+        assert!(find_deletions("\\begin{minted}{c}\nx", &markup, None, None).is_err());
+    }
+
+    #[test]
+    fn test_comment_deletion() {
+        let text = "ab % c\n% d\ne%";
+        assert_eq!(
+            comment_deletion(text, 3, 0),
+            Some(Deletion::range(2, 5, 0, Kind::Whole))
+        );
+        assert_eq!(
+            comment_deletion(text, 7, 1),
+            Some(Deletion::range(7, 10, 1, Kind::Whole))
+        );
+        assert_eq!(comment_deletion(text, 12, 2), None);
+    }
+
+    // ============ Verbatim ============
+
+    #[test]
+    fn test_verbatim_braces() -> Result<()> {
+        let mut text = String::from(
+            "\\alice{a}\n\\begin{verbatim}\n\\alice{ } }\n\\end{verbatim}\n\\begin{minted}{tex}\n{\n\\end{minted}\\alice{b}",
+        );
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(
+            text,
+            "a\n\\begin{verbatim}\n\\alice{ } }\n\\end{verbatim}\n\\begin{minted}{tex}\n{\n\\end{minted}b"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbatim_percent() -> Result<()> {
+        let mut text = String::from("\\begin{lstlisting}\n50% \n\\end{lstlisting}\\alice{x}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "\\begin{lstlisting}\n50% \n\\end{lstlisting}x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbatim_percent_before_end() -> Result<()> {
+        let mut text = String::from("\\begin{verbatim}100%\\end{verbatim} \\alice{x}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "\\begin{verbatim}100%\\end{verbatim} x");
+        // the unclosed `\verb` after the environment is not hidden in a comment
+        let text = "\\begin{verbatim}%\\end{verbatim}\\verb|x";
+        assert!(find_deletions(text, &unwrap(vec!["alice"]).into(), None, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verb() -> Result<()> {
+        let mut text = String::from("\\alice{\\verb|}| and \\verb*+%{+} \\verbatiminput{x}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "\\verb|}| and \\verb*+%{+ \\verbatiminput{x}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verb_unclosed_fails() {
+        for text in ["\\verb|x", "\\verb|x\n|"] {
+            assert!(find_deletions(text, &unwrap(vec!["alice"]).into(), None, None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_verbatim_extra_environment() -> Result<()> {
+        let mut text = String::from("\\begin{code}\n}\\alice{x}\n\\end{code}\\alice{y}");
+        let markup = Markup {
+            rules: unwrap(vec!["alice"]),
+            verbatim: vec!["code".to_string()],
+            ..Default::default()
+        };
+        let deletions = find_deletions(&text, &markup, None, None)?;
+        clean_text(&mut text, deletions);
+        assert_eq!(text, "\\begin{code}\n}\\alice{x}\n\\end{code}y");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbatim_line_numbers() -> Result<()> {
+        let text = "\\begin{verbatim}\na\nb\n\\end{verbatim}\n\\alice{x}";
+        let deletions = find_deletions(text, &unwrap(vec!["alice"]).into(), None, None)?;
+        assert_eq!(deletions[0].line, 4);
+        Ok(())
+    }
+
+    // ============ Tokens ============
+
+    #[test]
+    fn test_line_break_before_group() -> Result<()> {
+        let mut text = String::from("\\alice{a\\\\{b} c}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "a\\\\{b} c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_break_before_comment() -> Result<()> {
+        let mut text = String::from("\\alice{a\\\\% }\n}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "a\\\\% }\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_word_prefix() -> Result<()> {
+        let mut text = String::from("\\alicex{a} \\alice\\{ \\alice{b}");
+        clean(&mut text, vec!["alice"])?;
+        assert_eq!(text, "\\alicex{a} \\alice\\{ b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment_name_with_spaces() -> Result<()> {
+        let mut text = String::from("\\begin {alice}x\\end{ alice }");
+        clean_environments(&mut text, vec![("alice", Mode::Unwrap)])?;
+        assert_eq!(text, "x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_location() {
+        let text = "a\n  b}";
+        let errors = find_deletions(text, &unwrap(vec!["alice"]).into(), None, None).unwrap_err();
+        let error = &errors.0[0];
+        assert_eq!(error.kind, ErrorKind::UnmatchedClosingBrace);
+        assert_eq!((error.location.line, error.location.column), (1, 3));
+    }
+
+    #[test]
+    fn test_every_unclosed_bracket_is_reported() {
+        let text = "{a}{b\n\\alice{c \\bob{d}\n\\begin{x}{e\n";
+        let markup = Markup {
+            environments: vec![Environment::new("x", Mode::Unwrap)],
+            ..unwrap(vec!["alice", "bob"]).into()
+        };
+        let errors = find_deletions(text, &markup, None, None).unwrap_err();
+        let found: Vec<(ErrorKind, usize, usize)> = errors
+            .iter()
+            .map(|error| {
+                (
+                    error.kind.clone(),
+                    error.location.line,
+                    error.location.column,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (ErrorKind::UnclosedBrace, 0, 3),
+                (ErrorKind::UnclosedCommand("alice".to_string()), 1, 0),
+                (ErrorKind::UnclosedEnvironment("x".to_string()), 2, 0),
+                (ErrorKind::UnclosedBrace, 2, 9),
+            ]
+        );
+        assert_eq!(errors.0[1].snippet, "\\alice{c \\bob{d}");
+    }
+
+    #[test]
+    fn test_unmatched_end_notes() {
+        let markup = Markup {
+            environments: vec![
+                Environment::new("alice", Mode::Unwrap),
+                Environment::new("bob", Mode::Unwrap),
+            ],
+            ..Markup::default()
+        };
+        let notes = |text: &str| -> Vec<(String, usize)> {
+            let errors = find_deletions(text, &markup, None, None).unwrap_err();
+            errors.0[0]
+                .notes
+                .iter()
+                .map(|note| (note.message.clone(), note.location.offset))
+                .collect()
+        };
+        assert_eq!(
+            notes("\\begin{bob}\\begin{alice}\\end{bob}"),
+            vec![(
+                "the last open environment is \\begin{alice}".to_string(),
+                11
+            )]
+        );
+        assert_eq!(
+            notes("\\begin{bob} {x \\end{bob}"),
+            vec![(
+                "this bracket is not closed before \\end{bob}".to_string(),
+                12
+            )]
+        );
+        assert_eq!(
+            notes("{\\begin{bob}} \\end{bob}"),
+            vec![(
+                "\\begin{bob} is inside a group which ends earlier".to_string(),
+                1
+            )]
+        );
+        assert!(notes("\\end{bob}").is_empty());
+    }
+
+    // ============ Occurrences ============
+
+    #[test]
+    fn test_occurrences() -> Result<()> {
+        let text = "\\iffalse a\\else b\\fi\n\\replaced{new}{old} \\todo{x}\n\\begin{note}\ny\n\\end{note}% c\n";
+        let cleaner = Cleaner::new()
+            .accept_changes()
+            .drop_command("todo")
+            .unwrap_environment("note")
+            .strip_dead(true)
+            .strip_comments(true);
+        let occurrences = cleaner.occurrences(text)?;
+        let found: Vec<(&str, Vec<&str>, usize, usize)> = occurrences
+            .iter()
+            .map(|o| (o.name.as_str(), o.kept(text), o.line, o.end_line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("iffalse", vec![" b"], 0, 0),
+                ("replaced", vec!["new"], 1, 1),
+                ("todo", vec![], 1, 1),
+                ("note", vec!["y\n"], 2, 4),
+                ("%", vec![], 4, 4),
+            ]
+        );
+        assert_eq!(
+            &text[occurrences[2].start()..=occurrences[2].end()],
+            "\\todo{x}"
+        );
+        assert_eq!(cleaner.clean(text)?.occurrences, occurrences);
+        Ok(())
+    }
+
+    #[test]
+    fn test_occurrence_content() -> Result<()> {
+        let text = "\\alice{a \\bob{b}} \\todo[inline]{c {d}} \\replaced{e}{f}";
+        let cleaner = Cleaner::new()
+            .unwrap_command("alice")
+            .unwrap_command("bob")
+            .drop_command("todo")
+            .accept_changes();
+        let contents: Vec<Vec<&str>> = cleaner
+            .occurrences(text)?
+            .iter()
+            .map(|occurrence| occurrence.content(text))
+            .collect();
+        assert_eq!(
+            contents,
+            [vec!["a \\bob{b}"], vec!["b"], vec!["c {d}"], vec!["e"]]
+        );
+
+        let text = "\\begin{note}\nhello world\n\\end{note}\n\\todo[see {x}]{note}\n\\iffalse old \\fi\n\\textcolor{red}{a}";
+        let cleaner = Cleaner::new()
+            .drop_environment("note")
+            .drop_command("todo")
+            .rule("textcolor:drop,drop".parse()?)
+            .strip_dead(true);
+        let contents: Vec<Vec<&str>> = cleaner
+            .occurrences(text)?
+            .iter()
+            .map(|occurrence| occurrence.content(text))
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                vec!["\nhello world\n"],
+                vec!["note"],
+                vec![" old "],
+                vec!["red", "a"]
+            ]
+        );
+
+        // arguments are separate pieces
+        let text = "\\replaced{new text}{old text} \\alice[Note:]{text}";
+        let cleaner = Cleaner::new()
+            .rule("replaced:keep,keep".parse()?)
+            .unwrap_command("alice")
+            .keep_optional(true);
+        let contents: Vec<Vec<&str>> = cleaner
+            .occurrences(text)?
+            .iter()
+            .map(|occurrence| occurrence.content(text))
+            .collect();
+        assert_eq!(
+            contents,
+            [vec!["new text", "old text"], vec!["Note:", "text"]]
+        );
+        Ok(())
+    }
+
+    // ============ Includes ============
+
+    #[test]
+    fn test_find_includes() {
+        let text = "\\input{intro}\\include {a/b.tex}\n% \\input{commented}\n\\subfile{c}\\import{d/}{e}\\subimport{f}{ g }\\includegraphics{h}\\input";
+        let includes = find_includes(text, &[]);
+        assert_eq!(&text[includes[1].1.clone()], "\\include {a/b.tex}");
+        assert_eq!(
+            includes
+                .into_iter()
+                .map(|(include, _)| include)
+                .collect::<Vec<_>>(),
+            vec![
+                Include::Input("intro".to_string()),
+                Include::Input("a/b.tex".to_string()),
+                Include::Subfile("c".to_string()),
+                Include::Import("d/".to_string(), "e".to_string()),
+                Include::Subimport("f".to_string(), "g".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_includes_in_verbatim() {
+        let text = "\\begin{verbatim}\n\\input{a}\n\\end{verbatim}\\begin{code}\\input{b}\\end{code}\\input{c}";
+        let includes = find_includes(text, &["code".to_string()]);
+        assert_eq!(includes.len(), 1);
+        assert_eq!(includes[0].0, Include::Input("c".to_string()));
+    }
+
+    #[test]
+    fn test_resolve_include() {
+        let (root, base, dir) = (Path::new("p"), Path::new("p/i"), Path::new("p/s"));
+        let resolve = |include: Include| include.resolve(root, base, dir);
+        assert_eq!(
+            resolve(Include::Input("a".to_string())),
+            (PathBuf::from("p/i/a"), PathBuf::from("p/i"))
+        );
+        assert_eq!(
+            resolve(Include::Subfile("x/a".to_string())),
+            (PathBuf::from("p/s/x/a"), PathBuf::from("p/s/x"))
+        );
+        assert_eq!(
+            resolve(Include::Import("x".to_string(), "a".to_string())),
+            (PathBuf::from("p/x/a"), PathBuf::from("p/x"))
+        );
+        assert_eq!(
+            resolve(Include::Subimport("x".to_string(), "a".to_string())),
+            (PathBuf::from("p/i/x/a"), PathBuf::from("p/i/x"))
+        );
+    }
+
+    #[test]
+    fn test_collect_files() -> Result<()> {
+        let dir = TempDir::new("includes")?;
+        let main = dir.write(
+            "main.tex",
+            "\\input{sections/a}\\input{sections/b.tex}\\input{missing}",
+        )?;
+        dir.write("sections/a.tex", "\\input{sections/c}\\input{main}")?;
+        dir.write("sections/b.tex", "")?;
+        dir.write("sections/c.tex", "\\input{sections/a}")?;
+
+        let (files, warnings) = collect_files(&main, &[])?;
+        let names: Vec<PathBuf> = files
+            .iter()
+            .map(|file| file.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "main.tex",
+                "sections/a.tex",
+                "sections/c.tex",
+                "sections/b.tex"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].file.as_ref(), Some(&main));
+        assert_eq!(warnings[0].location.column, 40);
+        Ok(())
+    }
+
+    // ============ Cleaner ============
+
+    #[test]
+    fn test_cleaner() -> Result<()> {
+        let cleaner = Cleaner::new()
+            .unwrap_command("alice")
+            .drop_command("todo")
+            .drop_environment("note")
+            .colors(["blue"]);
+        let cleaned = cleaner
+            .clean("\\alice{a}\\todo{b}\n\\begin{note}\nc\n\\end{note}\n{\\color{blue} d}")?;
+        assert_eq!(cleaned.text, "a\nd");
+        assert_eq!(cleaned.removed, 4);
+        assert_eq!(
+            cleaned.deletions[0],
+            Deletion::range(0, 6, 0, Kind::Opening)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleaner_keep_optional() -> Result<()> {
+        let cleaner = Cleaner::new()
+            .accept_changes()
+            .unwrap_command("alice")
+            .keep_optional(true);
+        let cleaned = cleaner.clean("\\alice[a]{b} \\added[id=x]{c}")?;
+        assert_eq!(cleaned.text, "ab c");
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleaner_lines() -> Result<()> {
+        let cleaner = Cleaner::new().unwrap_command("alice").lines(Some(2), None);
+        let cleaned = cleaner.clean("\\alice{a}\n\\alice{b}")?;
+        assert_eq!(cleaned.text, "\\alice{a}\nb");
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleaner_empty() {
+        assert!(Cleaner::new().is_empty());
+        assert!(Cleaner::new().strip_dead(false).is_empty());
+        assert!(!Cleaner::new().colors(Vec::<String>::new()).is_empty());
+        assert!(!Cleaner::new().reject_changes().is_empty());
+    }
+}
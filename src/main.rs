@@ -1,23 +1,173 @@
+use std::collections::HashSet;
 use std::fmt::Write;
-
-use anyhow::{bail, Result};
-use clap::{arg, ArgAction, Command};
+use std::io::{BufRead, Read, Write as _};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use clap::{arg, Arg, ArgAction, ArgMatches, Command};
+use collclean::tokenizer::{Token, TokenKind, Tokenizer};
+use collclean::{
+    collect_files, Cleaned, Cleaner, Deletion, Kind, Location, Occurrence, Rule,
+    VERBATIM_ENVIRONMENTS,
+};
+use serde_json::{json, Value};
+use similar::TextDiff;
 use yansi::Paint;
 
-fn main() -> Result<()> {
-    let matches = Command::new("collclean")
+use config::{Colors, Config, Format};
+
+mod config;
+#[cfg(test)]
+mod testing;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            print_error(&err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints errors in a text as `file:line:col: error: ...` with the offending lines and other errors with their causes.
+fn print_error(err: &anyhow::Error) {
+    match err.downcast_ref::<collclean::Errors>() {
+        Some(errors) => eprintln!("{errors}\n(no changes made)"),
+        None => eprintln!("{} {err:#}", Paint::red("Error:").bold()),
+    }
+}
+
+/// A flag which can also be turned off with `--name=false`, e.g., to override `.collclean.toml`.
+fn switch(arg: Arg) -> Arg {
+    arg.action(ArgAction::Set)
+        .value_name("BOOL")
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("true")
+        .value_parser(clap::value_parser!(bool))
+}
+
+/// Value of the flag `id` if it is given on the command line, otherwise `config`.
+fn flag(matches: &ArgMatches, id: &str, config: bool) -> bool {
+    matches.get_one::<bool>(id).copied().unwrap_or(config)
+}
+
+fn command() -> Command {
+    Command::new("collclean")
         .version("0.4.2")
         .author("Alexander Lindermayr <alexander.lindermayr97@gmail.com>")
         .about("Clean LaTeX files after a collaboration.")
         .arg(arg!(<FILE>))
-        .arg(arg!([COMMANDS]).required(true).num_args(1..))
+        .arg(arg!([COMMANDS]).num_args(1..))
+        .arg(
+            arg!(--unwrap <COMMAND>)
+                .required(false)
+                .num_args(1..)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--drop <COMMAND>)
+                .required(false)
+                .num_args(1..)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--env <ENVIRONMENT>)
+                .required(false)
+                .num_args(1..)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--"drop-env" <ENVIRONMENT>)
+                .required(false)
+                .num_args(1..)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--rule <RULE>)
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(|s: &str| s.parse::<Rule>()),
+        )
+        .arg(
+            arg!(--color[COLORS])
+                .num_args(0..=1)
+                .require_equals(true)
+                .value_delimiter(','),
+        )
+        .arg(switch(arg!(--accept)))
+        .arg(switch(arg!(--"strip-dead")))
+        .arg(switch(arg!(--"strip-comments")))
+        .arg(switch(arg!(--reject)).conflicts_with("accept"))
+        .arg(switch(arg!(--"keep-optional")))
+        .arg(
+            arg!(--"verbatim-env" <ENVIRONMENT>)
+                .required(false)
+                .num_args(1..)
+                .action(ArgAction::Append),
+        )
         .arg(arg!(-o - -output[output]))
         .arg(arg!(--from[from]).value_parser(clap::value_parser!(usize)))
         .arg(arg!(--to[to]).value_parser(clap::value_parser!(usize)))
-        .arg(arg!(--dry[dry]).action(ArgAction::SetTrue))
-        .get_matches();
+        .arg(switch(arg!(--dry)))
+        .arg(switch(arg!(--diff)))
+        .arg(arg!(-i - -interactive).action(ArgAction::SetTrue))
+        .arg(
+            arg!(--stats)
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["output", "dry", "diff", "format", "interactive", "check"]),
+        )
+        .arg(
+            arg!(--format <FORMAT>)
+                .required(false)
+                .value_parser(["text", "json"])
+                .conflicts_with("diff"),
+        )
+        .arg(
+            arg!(--context <N>)
+                .required(false)
+                .requires("diff")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            arg!(--check)
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["output", "dry", "diff", "format", "interactive"]),
+        )
+        .arg(
+            arg!(--recursive[recursive])
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["output", "from", "to"]),
+        )
+        .arg(arg!(--config <FILE>).required(false))
+        .arg(
+            arg!(--"no-config")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("config"),
+        )
+        .arg(
+            arg!(-j --jobs <N>)
+                .required(false)
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+}
 
-    let dry = matches.get_flag("dry");
+fn run() -> Result<()> {
+    let matches = command().get_matches();
+
+    let check = matches.get_flag("check");
+    let recursive = matches.get_flag("recursive");
+    let interactive = matches.get_flag("interactive");
+    let jobs = match matches.get_one::<u64>("jobs") {
+        // questions are asked one file after another
+        _ if interactive => 1,
+        Some(&jobs) => jobs as usize,
+        None => std::thread::available_parallelism().map_or(1, usize::from),
+    };
     let from_line = matches.get_one::<usize>("from").copied();
     let to_line = matches.get_one::<usize>("to").copied();
 
@@ -31,309 +181,533 @@ fn main() -> Result<()> {
         }
     }
 
-    let path = matches
-        .get_one::<String>("FILE")
-        .map(std::path::PathBuf::from);
-    let commands = matches
+    // further files may precede the commands
+    let mut commands: Vec<String> = matches
         .get_many::<String>("COMMANDS")
-        .expect("no commands")
-        .map(|s| s.as_str())
+        .into_iter()
+        .flatten()
+        .cloned()
         .collect();
-
-    let path = path.ok_or_else(|| anyhow::anyhow!("No file path provided"))?;
-    if !path.exists() {
-        bail!("File not found: {}", path.display());
+    let num_paths = commands.iter().take_while(|arg| is_path(arg)).count();
+    let paths: Vec<String> = matches
+        .get_one::<String>("FILE")
+        .into_iter()
+        .cloned()
+        .chain(commands.drain(..num_paths))
+        .collect();
+    if let Some(path) = commands.iter().find(|arg| is_path(arg)) {
+        bail!("Files must be given before the commands, but found {path} after them");
     }
 
-    let mut text = std::fs::read_to_string(&path)?;
-    let deletions = find_deletions(&text, commands, from_line, to_line)?;
-    print_deletions(&text, &deletions)?;
+    let config = if matches.get_flag("no-config") {
+        Config::default()
+    } else if let Some(path) = matches.get_one::<String>("config") {
+        config::load(Path::new(path))?
+    } else {
+        match config::find(&config_dir(&paths[0])) {
+            Some(path) => config::load(&path)?,
+            None => Config::default(),
+        }
+    };
 
-    if !dry {
-        let num = clean_text(&mut text, deletions)?;
-        println!("Removed {} commands!", num / 2);
-        if let Some(output) = matches
-            .get_one::<String>("output")
-            .map(std::path::PathBuf::from)
-        {
-            std::fs::write(output, text)?;
+    // options given on the command line override the configuration
+    let strings = |id: &str| matches.get_many::<String>(id).into_iter().flatten();
+    let list = |id: &str, config: &[String]| -> Vec<String> {
+        if matches.contains_id(id) {
+            strings(id).cloned().collect()
         } else {
-            std::fs::write(path, text)?;
+            config.to_vec()
         }
+    };
+    let dry = flag(&matches, "dry", config.dry);
+    let context = matches.get_one::<usize>("context").copied();
+    let format = matches.get_one::<String>("format");
+    let preview = if flag(&matches, "diff", format.is_none() && config.diff) {
+        Preview::Diff(context.or(config.context).unwrap_or(3))
+    } else {
+        Preview::Changes
+    };
+    let json = preview == Preview::Changes
+        && format.map_or(config.format == Some(Format::Json), |format| {
+            format == "json"
+        });
+
+    let mut cleaner = Cleaner::new()
+        .keep_optional(flag(&matches, "keep-optional", config.keep_optional))
+        .strip_dead(flag(&matches, "strip-dead", config.strip_dead))
+        .strip_comments(flag(&matches, "strip-comments", config.strip_comments))
+        .lines(from_line, to_line);
+    let unwrap = if commands.is_empty() && !matches.contains_id("unwrap") {
+        config.unwrap.clone()
+    } else {
+        commands.iter().chain(strings("unwrap")).cloned().collect()
+    };
+    for name in unwrap {
+        cleaner = cleaner.unwrap_command(&name);
+    }
+    for name in list("drop", &config.drop) {
+        cleaner = cleaner.drop_command(&name);
+    }
+    let rules = match matches.get_many::<Rule>("rule") {
+        Some(rules) => rules.cloned().collect(),
+        None => config.rules.clone(),
+    };
+    for rule in rules {
+        cleaner = cleaner.rule(rule);
+    }
+    // `--accept` and `--reject` override both settings
+    let (accept, reject) = if matches.contains_id("accept") || matches.contains_id("reject") {
+        (
+            flag(&matches, "accept", false),
+            flag(&matches, "reject", false),
+        )
+    } else {
+        (config.accept, config.reject)
+    };
+    if accept && reject {
+        bail!(
+            "accept and reject cannot both be set in {}",
+            config::FILE_NAME
+        );
     }
-    Ok(())
-}
-
-struct Pattern {
-    text: Vec<char>,
-    start: Option<usize>,
-    current: usize,
-    typ: Type,
-}
-
-impl Pattern {
-    fn new(text: &str, typ: Type) -> Self {
-        Self {
-            text: text.chars().collect(),
-            start: None,
-            current: 0,
-            typ,
+    if accept {
+        cleaner = cleaner.accept_changes();
+    }
+    if reject {
+        cleaner = cleaner.reject_changes();
+    }
+    if matches.contains_id("color") {
+        cleaner = cleaner.colors(strings("color"));
+    } else {
+        match &config.color {
+            Some(Colors::All(true)) => cleaner = cleaner.colors(Vec::<String>::new()),
+            Some(Colors::Only(colors)) => cleaner = cleaner.colors(colors),
+            Some(Colors::All(false)) | None => {}
         }
     }
-
-    fn len(&self) -> usize {
-        self.text.len()
+    for name in list("env", &config.env) {
+        cleaner = cleaner.unwrap_environment(&name);
     }
-
-    fn reset(&mut self) {
-        self.start = None;
-        self.current = 0;
+    for name in list("drop-env", &config.drop_env) {
+        cleaner = cleaner.drop_environment(&name);
+    }
+    let verbatim = list("verbatim-env", &config.verbatim_env);
+    for name in &verbatim {
+        cleaner = cleaner.verbatim_environment(name);
+    }
+    if cleaner.is_empty() {
+        bail!(
+            "No commands provided, neither as arguments nor in {}",
+            config::FILE_NAME
+        );
     }
 
-    fn next(&mut self, idx: usize, c: char) -> Option<(usize, usize)> {
-        if self.current >= self.text.len() && self.typ == Type::Command {
-            if c.is_whitespace() && c != '\n' {
-                self.current += 1;
-                return None;
-            } else if c == '{' {
-                self.current += 1;
-                let tmp = Some((self.start.unwrap(), self.current));
-                self.reset();
-                return tmp;
-            } else {
-                self.reset();
+    let mut files = expand_paths(&paths)?;
+    if files.iter().any(|file| is_std(file)) && (files.len() > 1 || recursive) {
+        bail!("Reading from stdin (-) only works with a single input");
+    }
+    if interactive && files.iter().any(|file| is_std(file)) {
+        bail!("--interactive reads the answers from stdin and cannot be used with -");
+    }
+    if recursive {
+        let mut included = vec![];
+        for file in &files {
+            let (found, warnings) = collect_files(file, &verbatim)?;
+            for warning in warnings {
+                eprintln!("{warning}");
             }
+            included.extend(found);
         }
+        files = included;
+        dedup_files(&mut files);
+    }
+    let output = matches.get_one::<String>("output").map(PathBuf::from);
+    if files.len() > 1 && (output.is_some() || from_line.is_some() || to_line.is_some()) {
+        bail!("--output, --from and --to can only be used with a single file");
+    }
 
-        if self.current < self.text.len() && self.text[self.current] != c {
-            self.reset();
-        }
+    if check {
+        return check_files(&files, &cleaner, jobs);
+    }
+    if matches.get_flag("stats") {
+        return print_stats(&files, &cleaner, &verbatim, jobs);
+    }
+    if json {
+        return print_json(&files, &cleaner, output.as_deref(), dry, interactive, jobs);
+    }
 
-        if self.current < self.text.len() && self.text[self.current] == c {
-            if self.start.is_none() {
-                self.start = Some(idx);
-            }
-            self.current += 1;
-            if self.current == self.text.len() && self.typ != Type::Command {
-                let tmp = self.start;
-                self.reset();
-                Some((tmp.unwrap(), self.len()))
-            } else {
-                None
+    if let [file] = files.as_slice() {
+        let mut report = String::new();
+        let num = clean_file(
+            file,
+            &cleaner,
+            output.as_deref(),
+            dry,
+            interactive,
+            preview,
+            &mut report,
+        );
+        let mut summary = String::new();
+        if !dry {
+            if let Ok(num) = num {
+                writeln!(summary, "Removed {num} commands!")?;
             }
+        }
+        // the preview must not mix with the cleaned text
+        if is_std(output.as_deref().unwrap_or(file)) {
+            eprint!("{report}{summary}");
+        } else if let Preview::Diff(_) = preview {
+            // keep the diff free of other output
+            print!("{report}");
+            eprint!("{summary}");
         } else {
-            self.reset();
-            None
+            print!("{report}{summary}");
+        }
+        num?;
+        return Ok(());
+    }
+
+    // files with errors are reported and left unchanged, while the others are cleaned
+    let mut removed = vec![];
+    let mut failed = 0;
+    let results = process_files(&files, jobs, |file, report| {
+        clean_file(file, &cleaner, None, dry, interactive, preview, report)
+    });
+    for (file, (report, result)) in files.iter().zip(results) {
+        // a diff names its files itself
+        if preview == Preview::Changes {
+            println!("{}", Paint::new(file.display()).bold());
+        }
+        print!("{report}");
+        match result {
+            Ok(num) => removed.push((file, num)),
+            Err(err) => {
+                print_error(&err);
+                failed += 1;
+            }
         }
     }
-}
-
-#[derive(Debug, Clone, PartialEq, PartialOrd, Ord, Eq)]
-struct Deletion {
-    start: usize,
-    end: usize,
-    line: usize,
-}
-
-impl Deletion {
-    fn range(start: usize, end: usize, line: usize) -> Self {
-        Deletion { start, end, line }
+    if !dry {
+        let mut summary = String::new();
+        for (file, num) in &removed {
+            writeln!(summary, "Removed {num} commands from {}", file.display())?;
+        }
+        let total: usize = removed.iter().map(|(_, num)| num).sum();
+        writeln!(
+            summary,
+            "Removed {total} commands in {} files!",
+            removed.len()
+        )?;
+        match preview {
+            Preview::Diff(_) => eprint!("{summary}"),
+            Preview::Changes => print!("{summary}"),
+        }
     }
-
-    fn len(&self) -> usize {
-        self.end - self.start + 1
+    if failed > 0 {
+        bail!(
+            "{failed} of {} files could not be cleaned (no changes made to them)",
+            files.len()
+        );
     }
+    Ok(())
 }
 
-#[derive(PartialEq)]
-enum Type {
-    // pattern has an implicit opening bracket
-    Command,
-    Other,
-}
-
-fn get_context_around(text: &str, byte_pos: usize, char_count: usize) -> String {
-    let char_indices: Vec<(usize, char)> = text.char_indices().collect();
-    let char_pos = char_indices
-        .iter()
-        .position(|(i, _)| *i >= byte_pos)
-        .unwrap_or(char_indices.len());
-
-    let start = char_pos.saturating_sub(char_count);
-    let end = (char_pos + char_count).min(char_indices.len());
-
-    char_indices[start..end].iter().map(|(_, c)| *c).collect()
+/// Whether a positional argument refers to files rather than a command.
+fn is_path(arg: &str) -> bool {
+    arg.contains(['/', '\\', '.', '*', '?'])
 }
 
-fn find_deletions(
-    text: &str,
-    commands: Vec<&str>,
-    from: Option<usize>,
-    to: Option<usize>,
-) -> Result<Vec<Deletion>> {
-    let mut patterns: Vec<Pattern> = commands
-        .into_iter()
-        .map(|comm| Pattern::new(&format!("\\{comm}"), Type::Command))
-        .collect();
-    patterns.push(Pattern::new("\\{", Type::Other));
-    patterns.push(Pattern::new("\\}", Type::Other));
-    patterns.push(Pattern::new("\\%", Type::Other));
-
-    let mut deletions: Vec<Deletion> = vec![];
-    let mut depth: usize = 0;
-    let mut deleted_depths: Vec<usize> = vec![];
-    let mut commented = false;
-    let mut line: usize = 0;
-    let mut deleted_commands: Vec<(Deletion, Deletion)> = vec![];
-
-    'chars: for (i, c) in text.char_indices() {
-        if c == '\n' {
-            line += 1;
-        }
-
-        if !commented {
-            for p in patterns.iter_mut() {
-                if let Some((s, len)) = p.next(i, c) {
-                    match p.typ {
-                        Type::Command => {
-                            deleted_depths.push(depth);
-                            depth += 1;
-                            let deletion = Deletion::range(s, s + len - 1, line);
-                            deletions.push(deletion);
-                        }
-                        Type::Other => {}
-                    }
-                    continue 'chars;
-                }
-            }
+/// Expands files, directories and glob patterns into the files to clean. Directories are searched
+/// recursively for `.tex` files.
+fn expand_paths(paths: &[String]) -> Result<Vec<PathBuf>> {
+    // like shells, wildcards do not match hidden files
+    let options = glob::MatchOptions {
+        require_literal_leading_dot: true,
+        ..Default::default()
+    };
+    let mut files = vec![];
+    for path in paths {
+        if path == "-" {
+            files.push(PathBuf::from(path));
+            continue;
         }
-
-        match c {
-            '}' if !commented => {
-                if depth == 0 {
-                    let context = get_context_around(text, i, 10);
-                    bail!("It seems that there is a closing bracket without opening counterpart! Stopping! (no changes made) {}", context)
-                }
-                depth -= 1;
-                if let Some(last) = deleted_depths.last() {
-                    if *last == depth {
-                        deleted_depths.pop();
-                        let opening = match deletions.pop() {
-                            Some(d) => d,
-                            None => {
-                                let context = get_context_around(text, i, 10);
-                                bail!("It seems that there is a closing bracket without matching opening bracket! Stopping! (no changes made) {}", context)
-                            }
-                        };
-                        let closing = Deletion::range(i, i, line);
-
-                        deleted_commands.push((opening, closing));
-                    }
+        if !Path::new(path).exists() && path.contains(['*', '?', '[']) {
+            let mut found = false;
+            for entry in
+                glob::glob_with(path, options).with_context(|| format!("Invalid pattern {path}"))?
+            {
+                let entry = entry?;
+                if entry.is_dir() {
+                    tex_files_in(&entry, &mut files)?;
+                } else if entry.extension().is_some_and(|ext| ext == "tex") {
+                    files.push(entry);
                 }
+                found = true;
             }
-            '{' if !commented => depth += 1,
-            '%' => {
-                commented = true;
+            if !found {
+                bail!("No files match {path}");
             }
-            '\n' => {
-                commented = false;
+        } else {
+            let path = PathBuf::from(path);
+            if path.is_dir() {
+                tex_files_in(&path, &mut files)?;
+            } else if path.exists() {
+                files.push(path);
+            } else {
+                bail!("File not found: {}", path.display());
             }
-            _ => {}
         }
     }
+    dedup_files(&mut files);
+    Ok(files)
+}
 
-    if depth > 0 || !deletions.is_empty() {
-        bail!("It seems that there is a opening bracket without closing counterpart! Stopping! (no changes made)")
+/// Adds all `.tex` files in `dir` and its subdirectories in sorted order, skipping hidden ones.
+fn tex_files_in(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+        if entry.is_dir() {
+            tex_files_in(&entry, files)?;
+        } else if entry.extension().is_some_and(|ext| ext == "tex") {
+            files.push(entry);
+        }
     }
+    Ok(())
+}
 
-    let mut final_deletions: Vec<Deletion> = deleted_commands
-        .into_iter()
-        .filter(|comm| {
-            let opening_line = comm.0.line + 1; // Convert to 1-indexed
-            let closing_line = comm.1.line + 1; // Convert to 1-indexed
-            let from_ok = from.is_none_or(|f| opening_line >= f && closing_line >= f);
-            let to_ok = to.is_none_or(|t| opening_line <= t && closing_line <= t);
-            from_ok && to_ok
-        })
-        .flat_map(|comm| vec![comm.0, comm.1])
-        .collect();
+/// Removes files that appear more than once, keeping the first occurrence.
+fn dedup_files(files: &mut Vec<PathBuf>) {
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(file.canonicalize().unwrap_or_else(|_| file.clone())));
+}
 
-    final_deletions.sort();
+/// Whether `path` is `-`, which stands for stdin or stdout.
+fn is_std(path: &Path) -> bool {
+    path == Path::new("-")
+}
 
-    Ok(final_deletions)
+/// Directory in which the search for `.collclean.toml` starts: the directory of the first input,
+/// or the working directory for stdin and glob patterns.
+fn config_dir(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let dir = if path.is_dir() {
+        path
+    } else if path.is_file() {
+        path.parent().unwrap_or(Path::new(""))
+    } else {
+        Path::new("")
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())
 }
 
-fn print_deletions(text: &str, deletions: &[Deletion]) -> Result<()> {
-    if deletions.is_empty() {
-        println!("No commands have been found!");
-        Ok(())
+/// Name of `path` in messages, which is `<stdin>` for `-`.
+fn display_name(path: &Path) -> &Path {
+    if is_std(path) {
+        Path::new("<stdin>")
     } else {
-        let mut line_start: usize = 0;
-        let mut deletions_iter = deletions.iter();
-        let mut current = deletions_iter.next();
+        path
+    }
+}
 
-        for (l, line) in text.lines().enumerate() {
-            if current.is_none() {
-                break;
-            }
+/// Reads a file, or stdin for `-`.
+fn read_text(path: &Path) -> Result<String> {
+    if is_std(path) {
+        let mut text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .context("Failed to read from stdin")?;
+        Ok(text)
+    } else {
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+    }
+}
 
-            let line_len = line.len();
-            let line_end = line_start + line_len;
-            let mut line_deletions = vec![];
+/// How the changes to a file are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Preview {
+    // the removed parts of each affected line
+    Changes,
+    // a unified diff with this many lines of context
+    Diff(usize),
+}
 
-            while let Some(del) = current {
-                if del.line == l {
-                    line_deletions.push(del);
-                    current = deletions_iter.next();
-                } else {
-                    break;
-                }
-            }
+/// Cleans a file and writes a preview of the changes to `report`. If `interactive` is set, every
+/// occurrence is only removed after asking. The result is written to `output`, or back to the
+/// file, unless `dry` is set.
+///
+/// Returns the number of removed commands.
+fn clean_file(
+    path: &Path,
+    cleaner: &Cleaner,
+    output: Option<&Path>,
+    dry: bool,
+    interactive: bool,
+    preview: Preview,
+    report: &mut String,
+) -> Result<usize> {
+    let (text, cleaned) = clean(path, cleaner, output, dry, interactive)?;
+    match preview {
+        Preview::Changes => print_deletions(&text, &cleaned.deletions, report)?,
+        Preview::Diff(context) => {
+            print_diff(display_name(path), &text, &cleaned.text, context, report)?
+        }
+    }
+    Ok(cleaned.removed)
+}
 
-            if !line_deletions.is_empty() {
-                let mut string = String::new();
-                let line_str = format!("{}", format!("L{}: ", l + 1).dim());
-                string.write_str(&line_str)?;
-
-                let first_part = &text[line_start..line_deletions.first().unwrap().start];
-                add_part(first_part, &mut string, Side::Left)?;
-                let first_del = &text
-                    [line_deletions.first().unwrap().start..=line_deletions.first().unwrap().end];
-                add_del(first_del, &mut string)?;
-
-                for w in line_deletions.windows(2) {
-                    let gap = &text[w[0].end + 1..w[1].start];
-                    add_part(gap, &mut string, Side::Center)?;
-                    let del = &text[w[1].start..=w[1].end];
-                    add_del(del, &mut string)?;
-                }
+/// Cleans a file like `clean_file`, and returns its text together with the cleaned version.
+fn clean(
+    path: &Path,
+    cleaner: &Cleaner,
+    output: Option<&Path>,
+    dry: bool,
+    interactive: bool,
+) -> Result<(String, Cleaned)> {
+    let text = read_text(path)?;
+    let mut occurrences = cleaner
+        .occurrences(&text)
+        .map_err(|errors| errors.in_file(display_name(path)))?;
+    if interactive {
+        let mut input = std::io::stdin().lock();
+        occurrences = review(path, &text, occurrences, &mut input, &mut std::io::stderr())?;
+    }
+    let cleaned = Cleaned::new(&text, occurrences);
 
-                let last_part = &text[line_deletions.last().unwrap().end + 1..line_end];
-                add_part(last_part, &mut string, Side::Right)?;
+    if !dry {
+        let output = output.unwrap_or(path);
+        if is_std(output) {
+            std::io::stdout().write_all(cleaned.text.as_bytes())?;
+        } else {
+            std::fs::write(output, &cleaned.text)?;
+        }
+    }
+    Ok((text, cleaned))
+}
 
-                string.retain(|c| c != '\n' && c != '\r');
+/// Writes a unified diff of `text` and its `cleaned` version with `context` lines around each
+/// change to `out`, using `name` for both files. Nothing is written if the texts are equal.
+fn print_diff(
+    name: &Path,
+    text: &str,
+    cleaned: &str,
+    context: usize,
+    out: &mut String,
+) -> Result<()> {
+    let name = name.display().to_string();
+    let diff = TextDiff::from_lines(text, cleaned);
+    write!(
+        out,
+        "{}",
+        diff.unified_diff()
+            .context_radius(context)
+            .header(&name, &name)
+    )?;
+    Ok(())
+}
 
-                println!("{string}");
-            }
+/// Writes a preview of the deletions, one line per group of deletions sharing a line, to `out`.
+fn print_deletions(text: &str, deletions: &[Deletion], out: &mut String) -> Result<()> {
+    if deletions.is_empty() {
+        writeln!(out, "No commands have been found!")?;
+        return Ok(());
+    }
+
+    let deletions = outermost(deletions);
+    let mut i = 0;
+    while i < deletions.len() {
+        // deletions are printed together if they share a line
+        let first = deletions[i];
+        let line_start = text[..first.start].rfind('\n').map_or(0, |p| p + 1);
+        let mut line_end = line_end_after(text, first);
+        let mut j = i + 1;
+        while j < deletions.len() && deletions[j].start < line_end {
+            line_end = line_end_after(text, deletions[j]);
+            j += 1;
+        }
+        let line_deletions = &deletions[i..j];
+
+        let mut string = String::new();
+        let last = line_deletions.last().unwrap();
+        let last_line = first.line
+            + text.as_bytes()[first.start..last.end]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count();
+        let label = if last_line == first.line {
+            format!("L{}: ", first.line + 1)
+        } else {
+            format!("L{}-{}: ", first.line + 1, last_line + 1)
+        };
+        string.write_str(&format!("{}", label.dim()))?;
+
+        let first_part = &text[line_start..first.start];
+        add_part(first_part, &mut string, Side::Left)?;
+        add_del(&text[first.start..=first.end], &mut string)?;
+
+        for w in line_deletions.windows(2) {
+            let gap = &text[w[0].end + 1..w[1].start];
+            add_part(gap, &mut string, Side::Center)?;
+            let del = &text[w[1].start..=w[1].end];
+            add_del(del, &mut string)?;
+        }
 
-            // Handle both Unix (\n) and Windows (\r\n) line endings
-            let mut next_start = line_end;
-            if text[next_start..].starts_with("\r\n") {
-                next_start += 2;
-            } else if text[next_start..].starts_with('\n') {
-                next_start += 1;
-            } else if next_start < text.len() {
-                // No newline at end of file, we're done
-                next_start = text.len();
-            }
-            line_start = next_start;
+        let last_part = &text[last.end + 1..line_end];
+        add_part(last_part, &mut string, Side::Right)?;
+
+        // Handle both Unix (\n) and Windows (\r\n) line endings
+        string.retain(|c| c != '\n' && c != '\r');
+
+        writeln!(out, "{string}")?;
+        i = j;
+    }
+    Ok(())
+}
+
+/// Returns the deletions that are not contained in a previous one, e.g. commands inside a dropped
+/// command.
+fn outermost(deletions: &[Deletion]) -> Vec<&Deletion> {
+    let mut result: Vec<&Deletion> = vec![];
+    for del in deletions {
+        if result.last().is_none_or(|last| del.start > last.end) {
+            result.push(del);
         }
-        Ok(())
     }
+    result
+}
+
+/// End of the line on which the deletion ends, or right after it if it removes the line break.
+fn line_end_after(text: &str, del: &Deletion) -> usize {
+    if text.as_bytes()[del.end] == b'\n' {
+        del.end + 1
+    } else {
+        // `del.end` is inclusive and may be inside a multi-byte character
+        end_of_line(text, del.end + 1)
+    }
+}
+
+fn end_of_line(text: &str, pos: usize) -> usize {
+    text[pos..].find('\n').map_or(text.len(), |p| pos + p)
 }
 
 fn add_del(part: &str, string: &mut String) -> Result<()> {
-    string.write_str(&format!("{}", Paint::red(part).bold()))?;
+    // deletions of whole commands may span several lines
+    let part = part.lines().collect::<Vec<_>>().join(" ");
+    string.write_str(&format!("{}", Paint::red(&part).bold()))?;
     Ok(())
 }
 
@@ -380,227 +754,590 @@ fn add_part(part: &str, string: &mut String, side: Side) -> Result<()> {
     Ok(())
 }
 
-fn clean_text(text: &mut String, deletions: Vec<Deletion>) -> Result<usize> {
-    let mut deleted: usize = 0;
-    let num = deletions.len();
-    for del in deletions {
-        let start = del.start - deleted;
-        let end = del.end - deleted;
-        text.replace_range(start..=end, "");
-        deleted += del.len();
+/// Preview and result of processing a file, which is the number of removed commands by default.
+type Report<T = usize> = (String, Result<T>);
+
+/// The lines of an occurrence and the lines around it, with the removed parts highlighted.
+fn show_occurrence(text: &str, occurrence: &Occurrence) -> String {
+    let removed = |pos: usize| {
+        occurrence
+            .deletions
+            .iter()
+            .any(|deletion| (deletion.start..=deletion.end).contains(&pos))
+    };
+    let first = occurrence.line.saturating_sub(1);
+    let last = occurrence.end_line + 1;
+    let width = (last + 1).to_string().len();
+    let mut shown = String::new();
+    let mut start = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        if (first..=last).contains(&i) {
+            let line = line.trim_end_matches(['\n', '\r']);
+            let mut highlighted = String::new();
+            // split the line into runs of removed and kept characters
+            let mut run_start = 0;
+            for (offset, _) in line.char_indices().skip(1).chain([(line.len(), ' ')]) {
+                if offset < line.len() && removed(start + offset) == removed(start + run_start) {
+                    continue;
+                }
+                let run = &line[run_start..offset];
+                if removed(start + run_start) {
+                    highlighted.push_str(&run.red().bold().to_string());
+                } else {
+                    highlighted.push_str(run);
+                }
+                run_start = offset;
+            }
+            shown.push_str(&format!("{:>width$} | {highlighted}\n", (i + 1).dim()));
+        }
+        start += line.len();
     }
-
-    Ok(num)
+    shown
 }
 
-#[cfg(test)]
-mod test_clean {
-    use super::*;
-
-    fn clean(text: &mut String, commands: Vec<&str>) -> Result<usize> {
-        let deletions = find_deletions(text, commands, None, None)?;
-        clean_text(text, deletions)
+/// Asks for every occurrence in `text` whether it is removed, reading the answers from `input`.
+/// Answering `a` removes all further occurrences of the same command, and `q` (or the end of
+/// `input`) keeps all further occurrences which are not removed this way.
+///
+/// Returns the occurrences to remove.
+fn review(
+    path: &Path,
+    text: &str,
+    occurrences: Vec<Occurrence>,
+    input: &mut impl BufRead,
+    output: &mut impl std::io::Write,
+) -> Result<Vec<Occurrence>> {
+    let total = occurrences.len();
+    let mut remove = vec![];
+    let mut remove_all = HashSet::new();
+    let mut quit = false;
+    for (i, occurrence) in occurrences.into_iter().enumerate() {
+        if remove_all.contains(&occurrence.name) {
+            remove.push(occurrence);
+            continue;
+        }
+        if quit {
+            continue;
+        }
+        let location = Location::at(text, occurrence.start());
+        writeln!(
+            output,
+            "{}:{}:{}: {} ({}/{total})",
+            display_name(path).display(),
+            location.line + 1,
+            location.column + 1,
+            Paint::new(&occurrence.name).bold(),
+            i + 1
+        )?;
+        write!(output, "{}", show_occurrence(text, &occurrence))?;
+        loop {
+            write!(output, "Remove? [y,n,a,q,?] ")?;
+            output.flush()?;
+            let mut answer = String::new();
+            if input.read_line(&mut answer)? == 0 {
+                quit = true;
+                break;
+            }
+            match answer.trim() {
+                "y" => remove.push(occurrence),
+                "n" => {}
+                "a" => {
+                    remove_all.insert(occurrence.name.clone());
+                    remove.push(occurrence);
+                }
+                "q" => quit = true,
+                _ => {
+                    writeln!(output, "y - remove this occurrence")?;
+                    writeln!(output, "n - keep this occurrence")?;
+                    writeln!(
+                        output,
+                        "a - remove this and all further occurrences of {}",
+                        occurrence.name
+                    )?;
+                    writeln!(output, "q - quit and keep all further occurrences")?;
+                    continue;
+                }
+            }
+            break;
+        }
+        writeln!(output)?;
     }
+    Ok(remove)
+}
 
-    #[test]
-    fn test_no_clean() -> Result<()> {
-        let mut text1 = String::from("\\{  \\}");
-        let mut text2 = String::from("{  }");
-        clean(&mut text1, vec![])?;
-        clean(&mut text2, vec![])?;
-        assert_eq!(text1, "\\{  \\}");
-        assert_eq!(text2, "{  }");
-        Ok(())
-    }
+/// Byte range, 1-indexed line and column of the removed range `start..=end` of `text`.
+fn range_json(text: &str, start: usize, end: usize) -> Value {
+    let location = Location::at(text, start);
+    json!({
+        "start": start,
+        "end": end + 1,
+        "line": location.line + 1,
+        "column": location.column + 1,
+    })
+}
 
-    #[test]
-    fn test_simple_clean() -> Result<()> {
-        let mut text = String::from("\\anew{ab}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "ab");
-        Ok(())
-    }
+/// All removed markup of a file, with byte ranges (excluding their end) and 1-indexed lines and
+/// columns.
+fn file_json(path: &Path, text: &str, cleaned: &Cleaned) -> Value {
+    let occurrences: Vec<Value> = cleaned
+        .occurrences
+        .iter()
+        .map(|occurrence| {
+            let deletions: Vec<Value> = occurrence
+                .deletions
+                .iter()
+                .map(|deletion| {
+                    let mut json = range_json(text, deletion.start, deletion.end);
+                    let kind = match deletion.kind {
+                        Kind::Opening => "opening",
+                        Kind::Between => "between",
+                        Kind::Closing => "closing",
+                        Kind::Whole => "whole",
+                    };
+                    json["kind"] = json!(kind);
+                    json
+                })
+                .collect();
+            let mut json = range_json(text, occurrence.start(), occurrence.end());
+            json["command"] = json!(occurrence.name);
+            json["kept"] = json!(occurrence.kept(text));
+            json["deletions"] = json!(deletions);
+            json
+        })
+        .collect();
+    json!({
+        "file": path.display().to_string(),
+        "removed": cleaned.removed,
+        "occurrences": occurrences,
+    })
+}
 
-    #[test]
-    fn test_clean() -> Result<()> {
-        let mut text = String::from("\\anew{ a{v}b \\% \\{ }");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, " a{v}b \\% \\{ ");
-        Ok(())
-    }
+/// The errors of a file that could not be cleaned.
+fn error_json(path: &Path, err: &anyhow::Error) -> Value {
+    let errors: Vec<Value> = match err.downcast_ref::<collclean::Errors>() {
+        Some(errors) => errors
+            .iter()
+            .map(|error| {
+                json!({
+                    "line": error.location.line + 1,
+                    "column": error.location.column + 1,
+                    "message": error.kind.to_string(),
+                })
+            })
+            .collect(),
+        None => vec![json!({ "message": format!("{err:#}") })],
+    };
+    json!({
+        "file": display_name(path).display().to_string(),
+        "errors": errors,
+    })
+}
 
-    #[test]
-    fn test_clean_double_fake() -> Result<()> {
-        let mut text = String::from("\\ane\\anew{ a{v}b \\% \\{ }");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\ane a{v}b \\% \\{ ");
-        Ok(())
+/// Cleans `files` like `clean_file` and prints a JSON report of all removed markup instead of the
+/// preview. Files with errors are listed with their errors.
+fn print_json(
+    files: &[PathBuf],
+    cleaner: &Cleaner,
+    output: Option<&Path>,
+    dry: bool,
+    interactive: bool,
+    jobs: usize,
+) -> Result<()> {
+    let results = process_files(files, jobs, |file, _| {
+        let (text, cleaned) = clean(file, cleaner, output, dry, interactive)?;
+        Ok((
+            file_json(display_name(file), &text, &cleaned),
+            cleaned.removed,
+        ))
+    });
+    let mut reports = vec![];
+    let mut removed = 0;
+    let mut failed = 0;
+    for (file, (_, result)) in files.iter().zip(results) {
+        match result {
+            Ok((json, num)) => {
+                removed += num;
+                reports.push(json);
+            }
+            Err(err) => {
+                failed += 1;
+                reports.push(error_json(file, &err));
+            }
+        }
     }
-
-    #[test]
-    fn test_clean_double() -> Result<()> {
-        let mut text = String::from("\\anew{\\anew{ a{v}b \\% \\{ }}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, " a{v}b \\% \\{ ");
-        Ok(())
+    let json = json!({
+        "files": reports,
+        "summary": {
+            "files": files.len(),
+            "failed": failed,
+            "removed": removed,
+        },
+    });
+    let json = serde_json::to_string_pretty(&json)?;
+    // the report must not mix with the cleaned text
+    let to_stdout = !dry && files.iter().any(|file| is_std(output.unwrap_or(file)));
+    if to_stdout {
+        eprintln!("{json}");
+    } else {
+        println!("{json}");
     }
-
-    #[test]
-    fn test_clean_2() -> Result<()> {
-        let mut text = String::from("\\anew{\\nnew{ a{v}b} \\{ }");
-        clean(&mut text, vec!["anew", "nnew"])?;
-        assert_eq!(text, " a{v}b \\{ ");
-        Ok(())
+    if failed > 0 {
+        bail!(
+            "{failed} of {} files could not be cleaned (no changes made to them)",
+            files.len()
+        );
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_clean_with_whitespace() -> Result<()> {
-        let mut text = String::from("\\anew   { { a{v}b} \\{ }");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, " { a{v}b} \\{ ");
-        Ok(())
-    }
+/// Writes every occurrence of markup in a file to `report` as `file:line:col: found ...`, without
+/// changing the file.
+///
+/// Returns the number of occurrences.
+fn check_file(path: &Path, cleaner: &Cleaner, report: &mut String) -> Result<usize> {
+    let text = read_text(path)?;
+    let name = display_name(path);
+    let deletions = cleaner
+        .deletions(&text)
+        .map_err(|errors| errors.in_file(name))?;
+    let mut found = 0;
+    // every command or environment starts with an opening deletion, or is removed as a whole
+    for deletion in deletions
+        .iter()
+        .filter(|deletion| matches!(deletion.kind, Kind::Opening | Kind::Whole))
+    {
+        let location = Location::at(&text, deletion.start);
+        let markup = text[deletion.start..=deletion.end]
+            .lines()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or_default();
+        let markup: String = markup.trim().chars().take(40).collect();
+        writeln!(
+            report,
+            "{}:{}:{}: found {}",
+            name.display(),
+            location.line + 1,
+            location.column + 1,
+            Paint::red(&markup).bold()
+        )?;
+        found += 1;
+    }
+    Ok(found)
+}
 
-    #[test]
-    fn test_clean_with_whitespace_double() -> Result<()> {
-        let mut text = String::from("\\anew  \\anew  { { a{v}b} \\{ }");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\anew   { a{v}b} \\{ ");
-        Ok(())
+/// Checks `files` for remaining markup without changing them. Fails if any markup or an error is
+/// found.
+fn check_files(files: &[PathBuf], cleaner: &Cleaner, jobs: usize) -> Result<()> {
+    let results = process_files(files, jobs, |file, report| {
+        check_file(file, cleaner, report)
+    });
+    let mut found = 0;
+    let mut failed = 0;
+    for (report, result) in results {
+        print!("{report}");
+        match result {
+            Ok(num) => found += num,
+            Err(err) => {
+                print_error(&err);
+                failed += 1;
+            }
+        }
     }
-
-    #[test]
-    fn test_clean_with_whitespace_2() -> Result<()> {
-        let mut text = String::from("\\anew  f { { a{v}b} \\{ } \\anew { f}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\anew  f { { a{v}b} \\{ }  f");
-        Ok(())
+    if failed > 0 {
+        bail!("{failed} of {} files could not be checked", files.len());
     }
-
-    #[test]
-    fn test_clean_fails() -> Result<()> {
-        let mut text = String::from("{ } }");
-        assert!(clean(&mut text, vec!["anew"]).is_err());
-        Ok(())
+    if found > 0 {
+        bail!("Found {found} occurrences of markup");
     }
+    println!("No markup found!");
+    Ok(())
+}
 
-    #[test]
-    fn test_clean_fails_2() -> Result<()> {
-        let mut text = String::from("\\anew{ } }");
-        assert!(clean(&mut text, vec!["anew"]).is_err());
-        Ok(())
-    }
+/// An occurrence of markup counted by `--stats`.
+struct Found {
+    command: String,
+    // characters and words of the wrapped text
+    chars: usize,
+    words: usize,
+    // 1-indexed line of its start
+    line: usize,
+    // title of the surrounding chapter, section or subsection
+    section: Option<String>,
+}
 
-    #[test]
-    fn test_clean_no_fail() -> Result<()> {
-        let mut text = String::from("\\}\\}\\}");
-        assert!(clean(&mut text, vec!["anew"]).is_ok());
-        Ok(())
-    }
+/// Statistics of a command in all files.
+#[derive(Default)]
+struct CommandStats {
+    occurrences: usize,
+    chars: usize,
+    words: usize,
+    // 1-indexed lines of its occurrences in each file
+    lines: Vec<(PathBuf, Vec<usize>)>,
+    // number of occurrences in each section, in order of appearance
+    sections: Vec<(String, usize)>,
+}
 
-    #[test]
-    fn test_clean_no_comment_newline() -> Result<()> {
-        let mut text = String::from("% % \\anew{ } \n \\anew{}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "% % \\anew{ } \n ");
-        Ok(())
+/// Finds the titles of all chapters, sections and subsections outside of comments and verbatim
+/// text, together with the byte offset of their command. `verbatim` are further verbatim
+/// environments.
+fn find_sections(text: &str, verbatim: &[String]) -> Vec<(usize, String)> {
+    let tokens: Vec<Token> = Tokenizer::new(text)
+        .verbatim_environments(
+            VERBATIM_ENVIRONMENTS
+                .into_iter()
+                .chain(verbatim.iter().map(String::as_str)),
+        )
+        .collect();
+    let mut sections = vec![];
+    for (t, token) in tokens.iter().enumerate() {
+        if !(token.is(TokenKind::ControlWord)
+            && matches!(token.name(), "chapter" | "section" | "subsection"))
+        {
+            continue;
+        }
+        let mut next = skip_spaces(&tokens, t + 1);
+        if tokens.get(next).is_some_and(|next| next.text == "*") {
+            next = skip_spaces(&tokens, next + 1);
+        }
+        // a short title for the table of contents
+        if tokens
+            .get(next)
+            .is_some_and(|next| next.is(TokenKind::OpenBracket))
+        {
+            let Some(after) = skip_optional_arg(&tokens, next) else {
+                continue;
+            };
+            next = skip_spaces(&tokens, after);
+        }
+        if let Some(title) = group_content(text, &tokens, next) {
+            sections.push((token.start, title.trim().to_string()));
+        }
     }
+    sections
+}
 
-    #[test]
-    fn test_clean_no_newcommmand() -> Result<()> {
-        let mut text = String::from("\\newcommand{\\anew}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\newcommand{\\anew}");
-        Ok(())
-    }
+/// Index of the first token from `t` on which is not whitespace within the line.
+fn skip_spaces(tokens: &[Token], t: usize) -> usize {
+    t + tokens.get(t..).map_or(0, |rest| {
+        rest.iter()
+            .take_while(|token| token.is(TokenKind::Whitespace))
+            .count()
+    })
+}
 
-    // Tests for UTF-8 handling
-    #[test]
-    fn test_clean_with_unicode() -> Result<()> {
-        let mut text = String::from("\\anew{héllo wörld émoji 🎉}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "héllo wörld émoji 🎉");
-        Ok(())
+/// Index of the token after the optional argument starting with `[` at token `t`.
+fn skip_optional_arg(tokens: &[Token], t: usize) -> Option<usize> {
+    let mut brackets: usize = 0;
+    let mut braces: usize = 0;
+    for (i, token) in tokens.iter().enumerate().skip(t) {
+        match token.kind {
+            TokenKind::BeginGroup => braces += 1,
+            TokenKind::EndGroup => braces = braces.checked_sub(1)?,
+            TokenKind::OpenBracket if braces == 0 => brackets += 1,
+            TokenKind::CloseBracket if braces == 0 => {
+                brackets -= 1;
+                if brackets == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
     }
+    None
+}
 
-    #[test]
-    fn test_clean_unicode_command_content() -> Result<()> {
-        let mut text = String::from("Préfix \\anew{中文内容} Süffix");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "Préfix 中文内容 Süffix");
-        Ok(())
-    }
+/// Returns the content of the group starting at token `t`, which must not contain nested groups.
+fn group_content<'a>(text: &'a str, tokens: &[Token], t: usize) -> Option<&'a str> {
+    let open = tokens
+        .get(t)
+        .filter(|token| token.is(TokenKind::BeginGroup))?;
+    let close = tokens[t + 1..]
+        .iter()
+        .find(|token| matches!(token.kind, TokenKind::BeginGroup | TokenKind::EndGroup))?;
+    close
+        .is(TokenKind::EndGroup)
+        .then(|| &text[open.end..close.start])
+}
 
-    #[test]
-    fn test_clean_nested_unicode() -> Result<()> {
-        let mut text = String::from("\\alice{über \\bob{naïve} café}");
-        clean(&mut text, vec!["alice", "bob"])?;
-        assert_eq!(text, "über naïve café");
-        Ok(())
-    }
+/// Finds all markup in a file together with the text it wraps, without changing the file.
+fn find_markup(path: &Path, cleaner: &Cleaner, verbatim: &[String]) -> Result<Vec<Found>> {
+    let text = read_text(path)?;
+    let occurrences = cleaner
+        .occurrences(&text)
+        .map_err(|errors| errors.in_file(display_name(path)))?;
+    let sections = find_sections(&text, verbatim);
+    Ok(occurrences
+        .iter()
+        .map(|occurrence| {
+            let content = occurrence.content(&text);
+            let section = sections
+                .iter()
+                .rev()
+                .find(|(start, _)| *start <= occurrence.start())
+                .map(|(_, title)| title.clone());
+            Found {
+                command: occurrence.name.clone(),
+                chars: content.iter().map(|piece| piece.chars().count()).sum(),
+                // words do not continue from one argument to the next
+                words: content
+                    .iter()
+                    .map(|piece| piece.split_whitespace().count())
+                    .sum(),
+                line: occurrence.line + 1,
+                section,
+            }
+        })
+        .collect())
+}
 
-    // Tests for line range filtering
-    fn clean_with_range(
-        text: &mut String,
-        commands: Vec<&str>,
-        from: Option<usize>,
-        to: Option<usize>,
-    ) -> Result<usize> {
-        let deletions = find_deletions(text, commands, from, to)?;
-        clean_text(text, deletions)
+/// Sorted `lines` with consecutive lines combined into ranges, e.g. `3, 17-18, 40`.
+fn line_ranges(lines: &[usize]) -> String {
+    let mut lines = lines.to_vec();
+    lines.sort_unstable();
+    lines.dedup();
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for line in lines {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == line => *end = line,
+            _ => ranges.push((line, line)),
+        }
     }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-    #[test]
-    fn test_line_range_from() -> Result<()> {
-        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
-        clean_with_range(&mut text, vec!["anew"], Some(2), None)?;
-        assert_eq!(text, "\\anew{line1}\nline2\nline3");
-        Ok(())
+/// Prints for each command how often it occurs in `files`, how much text it wraps, and on which
+/// lines and in which sections it occurs, without changing the files.
+fn print_stats(
+    files: &[PathBuf],
+    cleaner: &Cleaner,
+    verbatim: &[String],
+    jobs: usize,
+) -> Result<()> {
+    let results = process_files(files, jobs, |file, _| find_markup(file, cleaner, verbatim));
+    // commands in order of their first occurrence
+    let mut stats: Vec<(String, CommandStats)> = vec![];
+    let mut failed = 0;
+    for (file, (_, result)) in files.iter().zip(results) {
+        let found = match result {
+            Ok(found) => found,
+            Err(err) => {
+                print_error(&err);
+                failed += 1;
+                continue;
+            }
+        };
+        for found in found {
+            let i = match stats
+                .iter()
+                .position(|(command, _)| *command == found.command)
+            {
+                Some(i) => i,
+                None => {
+                    stats.push((found.command.clone(), CommandStats::default()));
+                    stats.len() - 1
+                }
+            };
+            let command = &mut stats[i].1;
+            command.occurrences += 1;
+            command.chars += found.chars;
+            command.words += found.words;
+            match command.lines.last_mut() {
+                Some((last, lines)) if last == file => lines.push(found.line),
+                _ => command.lines.push((file.clone(), vec![found.line])),
+            }
+            if let Some(section) = found.section {
+                match command
+                    .sections
+                    .iter_mut()
+                    .find(|(title, _)| *title == section)
+                {
+                    Some((_, num)) => *num += 1,
+                    None => command.sections.push((section, 1)),
+                }
+            }
+        }
     }
 
-    #[test]
-    fn test_line_range_to() -> Result<()> {
-        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
-        clean_with_range(&mut text, vec!["anew"], None, Some(2))?;
-        assert_eq!(text, "line1\nline2\n\\anew{line3}");
-        Ok(())
+    if stats.is_empty() && failed == 0 {
+        println!("No commands have been found!");
     }
-
-    #[test]
-    fn test_line_range_from_to() -> Result<()> {
-        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
-        clean_with_range(&mut text, vec!["anew"], Some(2), Some(2))?;
-        assert_eq!(text, "\\anew{line1}\nline2\n\\anew{line3}");
-        Ok(())
+    stats.sort_by_key(|(_, command)| std::cmp::Reverse(command.occurrences));
+    for (name, command) in &stats {
+        println!(
+            "{}: {} occurrences wrapping {} characters and {} words",
+            Paint::new(name).bold(),
+            command.occurrences,
+            command.chars,
+            command.words
+        );
+        let lines: Vec<String> = command
+            .lines
+            .iter()
+            .map(|(file, lines)| match files {
+                [_] => line_ranges(lines),
+                _ => format!("{} {}", display_name(file).display(), line_ranges(lines)),
+            })
+            .collect();
+        println!("  lines: {}", lines.join("; "));
+        if !command.sections.is_empty() {
+            let sections: Vec<String> = command
+                .sections
+                .iter()
+                .map(|(title, num)| format!("{title} ({num})"))
+                .collect();
+            println!("  sections: {}", sections.join(", "));
+        }
     }
-
-    #[test]
-    fn test_line_range_inclusive() -> Result<()> {
-        // Verify that --to is inclusive (line 2 should be cleaned)
-        let mut text = String::from("\\anew{line1}\n\\anew{line2}\n\\anew{line3}");
-        clean_with_range(&mut text, vec!["anew"], Some(1), Some(2))?;
-        assert_eq!(text, "line1\nline2\n\\anew{line3}");
-        Ok(())
+    if failed > 0 {
+        bail!("{failed} of {} files could not be read", files.len());
     }
+    Ok(())
+}
 
-    // Tests for Windows line endings
-    #[test]
-    fn test_clean_windows_line_endings() -> Result<()> {
-        let mut text = String::from("\\anew{line1}\r\n\\anew{line2}\r\n\\anew{line3}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "line1\r\nline2\r\nline3");
-        Ok(())
-    }
+/// Runs `process` for `files` on up to `jobs` threads and returns the report and result of each
+/// file in the order of `files`.
+fn process_files<T: Send>(
+    files: &[PathBuf],
+    jobs: usize,
+    process: impl Fn(&Path, &mut String) -> Result<T> + Sync,
+) -> Vec<Report<T>> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Report<T>>>> = Mutex::new(files.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len().max(1)) {
+            scope.spawn(|| {
+                let mut i = next.fetch_add(1, Ordering::Relaxed);
+                while let Some(file) = files.get(i) {
+                    let mut report = String::new();
+                    let result = process(file, &mut report);
+                    results.lock().unwrap()[i] = Some((report, result));
+                    i = next.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every file is cleaned"))
+        .collect()
+}
 
-    #[test]
-    fn test_clean_mixed_line_endings() -> Result<()> {
-        let mut text = String::from("\\anew{line1}\n\\anew{line2}\r\n\\anew{line3}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "line1\nline2\r\nline3");
-        Ok(())
-    }
+#[cfg(test)]
+mod test_preview {
+    use super::*;
 
-    // Tests for helper functions
     #[test]
     fn test_take_first_chars_ascii() {
         assert_eq!(take_first_chars("hello world", 5), "hello");
@@ -630,431 +1367,341 @@ mod test_clean {
     }
 
     #[test]
-    fn test_get_context_around_ascii() {
-        let text = "0123456789abcdefghij";
-        assert_eq!(get_context_around(text, 10, 3), "789abc"); // 3 before 'a', 3 from 'a' onwards
-        assert_eq!(get_context_around(text, 0, 3), "012"); // at start, 3 chars from position 0
-        assert_eq!(get_context_around(text, 19, 3), "ghij"); // near end, 3 before 'j' + 'j'
-    }
-
-    #[test]
-    fn test_get_context_around_unicode() {
-        let text = "préfix中文süffix";
-        // Get context around the Chinese characters
-        let ctx = get_context_around(text, 7, 3); // Around '中'
-        assert!(ctx.chars().count() <= 6);
-        // Should not panic on any position
-        for (i, _) in text.char_indices() {
-            let _ = get_context_around(text, i, 5);
+    fn test_print_deletions_non_ascii() -> Result<()> {
+        for text in [
+            "Texte % à revoir: café\nsuite\n",
+            "\\begin{note}x\\end{note}\u{a0}",
+        ] {
+            let cleaner = Cleaner::new().drop_environment("note").strip_comments(true);
+            let cleaned = cleaner.clean(text)?;
+            let mut out = String::new();
+            print_deletions(text, &cleaned.deletions, &mut out)?;
+            assert_eq!(out.lines().count(), 1, "{text}");
         }
-    }
-
-    // Test error cases produce errors (not panics)
-    #[test]
-    fn test_unmatched_bracket_error_with_unicode() {
-        let text = String::from("héllo } wörld");
-        let result = find_deletions(&text, vec!["anew"], None, None);
-        assert!(result.is_err());
-    }
-
-    #[test]
-    fn test_unclosed_bracket_error() {
-        let text = String::from("\\anew{ unclosed");
-        let result = find_deletions(&text, vec!["anew"], None, None);
-        assert!(result.is_err());
-    }
-
-    // ============ Edge cases ============
-
-    #[test]
-    fn test_empty_file() -> Result<()> {
-        let mut text = String::from("");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "");
-        Ok(())
-    }
-
-    #[test]
-    fn test_whitespace_only() -> Result<()> {
-        let mut text = String::from("   \n\t\n   ");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "   \n\t\n   ");
-        Ok(())
-    }
-
-    #[test]
-    fn test_empty_command_content() -> Result<()> {
-        let mut text = String::from("\\anew{}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "");
-        Ok(())
-    }
-
-    #[test]
-    fn test_command_at_file_start() -> Result<()> {
-        let mut text = String::from("\\anew{start} middle end");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "start middle end");
-        Ok(())
-    }
-
-    #[test]
-    fn test_command_at_file_end() -> Result<()> {
-        let mut text = String::from("start middle \\anew{end}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "start middle end");
-        Ok(())
-    }
-
-    #[test]
-    fn test_multiple_commands_same_line() -> Result<()> {
-        let mut text = String::from("\\alice{one} and \\bob{two} and \\alice{three}");
-        clean(&mut text, vec!["alice", "bob"])?;
-        assert_eq!(text, "one and two and three");
-        Ok(())
-    }
-
-    #[test]
-    fn test_deeply_nested_commands() -> Result<()> {
-        let mut text = String::from("\\a{\\b{\\c{\\d{deep}}}}");
-        clean(&mut text, vec!["a", "b", "c", "d"])?;
-        assert_eq!(text, "deep");
-        Ok(())
-    }
-
-    #[test]
-    fn test_command_spanning_lines() -> Result<()> {
-        let mut text = String::from("\\anew{line1\nline2\nline3}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "line1\nline2\nline3");
-        Ok(())
-    }
-
-    #[test]
-    fn test_only_commands() -> Result<()> {
-        let mut text = String::from("\\anew{\\bob{\\charlie{x}}}");
-        clean(&mut text, vec!["anew", "bob", "charlie"])?;
-        assert_eq!(text, "x");
-        Ok(())
-    }
-
-    #[test]
-    fn test_adjacent_commands() -> Result<()> {
-        let mut text = String::from("\\a{x}\\b{y}\\c{z}");
-        clean(&mut text, vec!["a", "b", "c"])?;
-        assert_eq!(text, "xyz");
-        Ok(())
-    }
-
-    // ============ Whitespace handling ============
-
-    #[test]
-    fn test_tab_before_brace() -> Result<()> {
-        let mut text = String::from("\\anew\t{content}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "content");
-        Ok(())
-    }
-
-    #[test]
-    fn test_mixed_whitespace_before_brace() -> Result<()> {
-        let mut text = String::from("\\anew \t  {content}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "content");
-        Ok(())
-    }
-
-    #[test]
-    fn test_newline_before_brace_no_match() -> Result<()> {
-        // Newline between command and brace should NOT be treated as the command
-        let mut text = String::from("\\anew\n{content}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\anew\n{content}");
-        Ok(())
-    }
-
-    #[test]
-    fn test_content_with_leading_trailing_whitespace() -> Result<()> {
-        let mut text = String::from("\\anew{  spaced  }");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "  spaced  ");
-        Ok(())
-    }
-
-    // ============ LaTeX-specific patterns ============
-
-    #[test]
-    fn test_command_in_math_mode() -> Result<()> {
-        let mut text = String::from("$x = \\anew{y + z}$");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "$x = y + z$");
-        Ok(())
-    }
-
-    #[test]
-    fn test_command_in_display_math() -> Result<()> {
-        let mut text = String::from("\\[\n\\anew{A = B}\n\\]");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\[\nA = B\n\\]");
-        Ok(())
-    }
-
-    #[test]
-    fn test_similar_command_prefixes() -> Result<()> {
-        // Should only match exact command name
-        let mut text = String::from("\\anew{x} \\anewer{y} \\an{z}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "x \\anewer{y} \\an{z}");
-        Ok(())
-    }
-
-    #[test]
-    fn test_command_with_numbers() -> Result<()> {
-        let mut text = String::from("\\rev1{first} \\rev2{second}");
-        clean(&mut text, vec!["rev1", "rev2"])?;
-        assert_eq!(text, "first second");
-        Ok(())
-    }
-
-    #[test]
-    fn test_escaped_braces_in_content() -> Result<()> {
-        let mut text = String::from("\\anew{set \\{ a, b \\}}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "set \\{ a, b \\}");
-        Ok(())
-    }
-
-    #[test]
-    fn test_nested_regular_braces() -> Result<()> {
-        let mut text = String::from("\\anew{outer {inner {deep}} end}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "outer {inner {deep}} end");
         Ok(())
     }
+}
 
-    #[test]
-    fn test_latex_environments_preserved() -> Result<()> {
-        let mut text = String::from("\\begin{equation}\n\\anew{x = y}\n\\end{equation}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\begin{equation}\nx = y\n\\end{equation}");
-        Ok(())
-    }
+#[cfg(test)]
+mod test_files {
+    use super::*;
+    use crate::testing::TempDir;
 
-    // ============ Comment handling ============
+    // ============ Multiple files ============
 
     #[test]
-    fn test_comment_at_end_of_line() -> Result<()> {
-        let mut text = String::from("\\anew{visible} % \\anew{commented}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "visible % \\anew{commented}");
-        Ok(())
+    fn test_is_path() {
+        assert!(is_path("paper.tex"));
+        assert!(is_path("chapters/"));
+        assert!(is_path("chapters/**/*.tex"));
+        assert!(!is_path("alice"));
+        assert!(!is_path("todo"));
     }
 
     #[test]
-    fn test_multiple_comment_lines() -> Result<()> {
-        let mut text = String::from("% \\anew{a}\n% \\anew{b}\n\\anew{c}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "% \\anew{a}\n% \\anew{b}\nc");
-        Ok(())
-    }
-
-    #[test]
-    fn test_escaped_percent_not_comment() -> Result<()> {
-        let mut text = String::from("\\anew{100\\% done}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "100\\% done");
-        Ok(())
-    }
+    fn test_expand_paths() -> Result<()> {
+        let dir = TempDir::new("paths")?;
+        for name in [
+            "main.tex",
+            "notes.txt",
+            "chapters/b.tex",
+            "chapters/a.tex",
+            "chapters/part/c.tex",
+            "chapters/part/.hidden/d.tex",
+        ] {
+            dir.write(name, "")?;
+        }
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let expanded = |paths: &[String]| -> Result<Vec<String>> {
+            Ok(expand_paths(paths)?
+                .iter()
+                .map(|file| {
+                    file.strip_prefix(dir.path())
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect())
+        };
+        let from_dir = expanded(&[path("chapters"), path("chapters/a.tex")]);
+        let from_glob = expanded(&[path("*.t*"), path("chapters/**/*.tex")]);
+        let missing = expanded(&[path("missing.tex")]);
+        let no_match = expanded(&[path("*.bib")]);
 
-    #[test]
-    fn test_comment_then_code_same_line_next() -> Result<()> {
-        let mut text = String::from("% comment\n\\anew{code}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "% comment\ncode");
+        assert_eq!(
+            from_dir?,
+            ["chapters/a.tex", "chapters/b.tex", "chapters/part/c.tex"]
+        );
+        assert_eq!(
+            from_glob?,
+            [
+                "main.tex",
+                "chapters/a.tex",
+                "chapters/b.tex",
+                "chapters/part/c.tex"
+            ]
+        );
+        assert!(missing.is_err());
+        assert!(no_match.is_err());
         Ok(())
     }
 
-    // ============ Line range edge cases ============
-
     #[test]
-    fn test_line_range_first_line_only() -> Result<()> {
-        let mut text = String::from("\\anew{first}\n\\anew{second}");
-        clean_with_range(&mut text, vec!["anew"], Some(1), Some(1))?;
-        assert_eq!(text, "first\n\\anew{second}");
+    fn test_clean_files_in_order() -> Result<()> {
+        let dir = TempDir::new("jobs")?;
+        let mut files = vec![];
+        for i in 0..8 {
+            let text = if i == 5 {
+                "\\alice{".to_string()
+            } else {
+                "\\alice{x}".repeat(i)
+            };
+            files.push(dir.write(&format!("{i}.tex"), &text)?);
+        }
+        let cleaner = Cleaner::new().unwrap_command("alice");
+        let results = process_files(&files, 3, |file, report| {
+            clean_file(file, &cleaner, None, false, false, Preview::Changes, report)
+        });
+        let cleaned = std::fs::read_to_string(&files[2]);
+
+        for (i, (report, result)) in results.iter().enumerate() {
+            match i {
+                0 => assert_eq!(report, "No commands have been found!\n"),
+                5 => assert!(result.is_err()),
+                _ => {
+                    assert_eq!(report.lines().count(), 1);
+                    assert_eq!(*result.as_ref().unwrap(), i);
+                }
+            }
+        }
+        assert_eq!(cleaned?, "xx");
         Ok(())
     }
 
-    #[test]
-    fn test_line_range_last_line_only() -> Result<()> {
-        let mut text = String::from("\\anew{first}\n\\anew{second}");
-        clean_with_range(&mut text, vec!["anew"], Some(2), Some(2))?;
-        assert_eq!(text, "\\anew{first}\nsecond");
-        Ok(())
-    }
+    // ============ Check ============
 
     #[test]
-    fn test_line_range_spanning_command_excluded() -> Result<()> {
-        // Command spans lines 1-3, but range is only line 2
-        // Command should NOT be removed since it's not completely inside range
-        let mut text = String::from("\\anew{line1\nline2\nline3}");
-        clean_with_range(&mut text, vec!["anew"], Some(2), Some(2))?;
-        assert_eq!(text, "\\anew{line1\nline2\nline3}");
-        Ok(())
-    }
+    fn test_check_file() -> Result<()> {
+        let dir = TempDir::new("check")?;
+        let text = "a \\alice{b}\n\\begin{note}\nc\n\\end{note}\n\\todo{d\ne}\n";
+        let file = dir.write("paper.tex", text)?;
+        let cleaner = Cleaner::new()
+            .unwrap_command("alice")
+            .drop_command("todo")
+            .unwrap_environment("note");
+        let mut report = String::new();
+        let found = check_file(&file, &cleaner, &mut report);
+        let unchanged = std::fs::read_to_string(&file);
 
-    #[test]
-    fn test_line_range_spanning_command_included() -> Result<()> {
-        // Command spans lines 1-3, range includes all
-        let mut text = String::from("\\anew{line1\nline2\nline3}");
-        clean_with_range(&mut text, vec!["anew"], Some(1), Some(3))?;
-        assert_eq!(text, "line1\nline2\nline3");
+        assert_eq!(found?, 3);
+        assert_eq!(unchanged?, text);
+        let locations: Vec<&str> = report
+            .lines()
+            .map(|line| line.split(": found").next().unwrap())
+            .collect();
+        let name = file.display();
+        assert_eq!(
+            locations,
+            [
+                format!("{name}:1:3"),
+                format!("{name}:2:1"),
+                format!("{name}:5:1"),
+            ]
+        );
+        assert!(report.contains("\\todo{d"));
         Ok(())
     }
 
-    #[test]
-    fn test_line_range_no_commands_in_range() -> Result<()> {
-        let mut text = String::from("plain\n\\anew{marked}\nplain");
-        clean_with_range(&mut text, vec!["anew"], Some(1), Some(1))?;
-        assert_eq!(text, "plain\n\\anew{marked}\nplain");
-        Ok(())
-    }
+    // ============ Diff ============
 
     #[test]
-    fn test_line_range_from_zero_treated_as_start() -> Result<()> {
-        // from=0 should work (though 1-indexed, 0 means "from start")
-        let mut text = String::from("\\anew{first}\n\\anew{second}");
-        clean_with_range(&mut text, vec!["anew"], Some(0), Some(1))?;
-        assert_eq!(text, "first\n\\anew{second}");
-        Ok(())
-    }
-
-    // ============ Multiple/complex commands ============
+    fn test_print_diff() -> Result<()> {
+        let text = "a\nb \\alice{c}\nd\ne\nf\n";
+        let cleaner = Cleaner::new().unwrap_command("alice");
+        let cleaned = cleaner.clean(text)?;
+        let mut out = String::new();
+        print_diff(Path::new("paper.tex"), text, &cleaned.text, 1, &mut out)?;
+        assert_eq!(
+            out,
+            "--- paper.tex\n+++ paper.tex\n@@ -1,3 +1,3 @@\n a\n-b \\alice{c}\n+b c\n d\n"
+        );
 
-    #[test]
-    fn test_three_different_commands() -> Result<()> {
-        let mut text = String::from("\\alice{A} \\bob{B} \\charlie{C}");
-        clean(&mut text, vec!["alice", "bob", "charlie"])?;
-        assert_eq!(text, "A B C");
+        let mut out = String::new();
+        print_diff(Path::new("paper.tex"), text, text, 3, &mut out)?;
+        assert!(out.is_empty());
         Ok(())
     }
 
-    #[test]
-    fn test_same_command_repeated() -> Result<()> {
-        let mut text = String::from("\\x{1}\\x{2}\\x{3}\\x{4}\\x{5}");
-        clean(&mut text, vec!["x"])?;
-        assert_eq!(text, "12345");
-        Ok(())
-    }
+    // ============ JSON ============
 
     #[test]
-    fn test_interleaved_nested_commands() -> Result<()> {
-        let mut text = String::from("\\a{1\\b{2}3}\\b{4\\a{5}6}");
-        clean(&mut text, vec!["a", "b"])?;
-        assert_eq!(text, "123456");
-        Ok(())
-    }
+    fn test_file_json() -> Result<()> {
+        let text = "a\n\\alice[x]{b}";
+        let cleaner = Cleaner::new().unwrap_command("alice");
+        let json = file_json(Path::new("paper.tex"), text, &cleaner.clean(text)?);
+        assert_eq!(json["file"], "paper.tex");
+        assert_eq!(json["removed"], 1);
+        let occurrence = &json["occurrences"][0];
+        assert_eq!(occurrence["command"], "alice");
+        assert_eq!(occurrence["kept"], json!(["b"]));
+        assert_eq!(
+            (&occurrence["start"], &occurrence["end"]),
+            (&json!(2), &json!(14))
+        );
+        let opening = &occurrence["deletions"][0];
+        assert_eq!(opening["kind"], "opening");
+        assert_eq!(
+            &text[2..opening["end"].as_u64().unwrap() as usize],
+            "\\alice[x]{"
+        );
+        assert_eq!(
+            (&opening["line"], &opening["column"]),
+            (&json!(2), &json!(1))
+        );
 
-    #[test]
-    fn test_partial_command_match() -> Result<()> {
-        // "new" should not match "\anew"
-        let mut text = String::from("\\anew{content}");
-        clean(&mut text, vec!["new"])?;
-        assert_eq!(text, "\\anew{content}");
+        let errors = Cleaner::new().unwrap_command("alice").clean("\\alice{");
+        let err = anyhow::Error::from(errors.unwrap_err());
+        let json = error_json(Path::new("-"), &err);
+        assert_eq!(json["file"], "<stdin>");
+        assert_eq!(json["errors"][0]["line"], 1);
+        Ok(())
+    }
+
+    // ============ Interactive ============
+
+    #[test]
+    fn test_review() -> Result<()> {
+        let text = "\\alice{a}\\bob{b}\\alice{c}\n\\bob{d}\\alice{e}\\bob{f}\n";
+        let cleaner = Cleaner::new().unwrap_command("alice").unwrap_command("bob");
+        let cleaned = |answers: &str| -> Result<String> {
+            let occurrences = cleaner.occurrences(text)?;
+            let mut input = std::io::Cursor::new(answers);
+            let mut output = vec![];
+            let remove = review(
+                Path::new("paper.tex"),
+                text,
+                occurrences,
+                &mut input,
+                &mut output,
+            )?;
+            Ok(Cleaned::new(text, remove).text)
+        };
+        assert_eq!(
+            cleaned("y\nn\n?\nn\ny\ny\ny\n")?,
+            "a\\bob{b}\\alice{c}\ndef\n"
+        );
+        // remove all further \alice, then keep everything else
+        assert_eq!(
+            cleaned("n\nn\na\nq\n")?,
+            "\\alice{a}\\bob{b}c\n\\bob{d}e\\bob{f}\n"
+        );
+        // the end of the input keeps the remaining occurrences
+        assert_eq!(
+            cleaned("y\n")?,
+            "a\\bob{b}\\alice{c}\n\\bob{d}\\alice{e}\\bob{f}\n"
+        );
         Ok(())
     }
 
     #[test]
-    fn test_command_not_in_list() -> Result<()> {
-        let mut text = String::from("\\alice{A} \\bob{B}");
-        clean(&mut text, vec!["charlie"])?;
-        assert_eq!(text, "\\alice{A} \\bob{B}");
+    fn test_show_occurrence() -> Result<()> {
+        let text = "a\nb\nc \\alice{d}\ne\nf\n";
+        let occurrences = Cleaner::new().unwrap_command("alice").occurrences(text)?;
+        let shown = show_occurrence(text, &occurrences[0]);
+        let lines: Vec<&str> = shown.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("| b"));
+        assert!(lines[1].contains("\\alice{"));
+        assert!(lines[2].ends_with("| e"));
         Ok(())
     }
 
-    // ============ Error handling ============
+    // ============ Statistics ============
 
     #[test]
-    fn test_multiple_unmatched_closing() {
-        let text = String::from("text } more } end");
-        let result = find_deletions(&text, vec!["anew"], None, None);
-        assert!(result.is_err());
+    fn test_line_ranges() {
+        assert_eq!(line_ranges(&[40, 3, 17, 18, 18]), "3, 17-18, 40");
+        assert_eq!(line_ranges(&[1, 2, 3]), "1-3");
+        assert_eq!(line_ranges(&[]), "");
     }
 
     #[test]
-    fn test_multiple_unmatched_opening() {
-        let text = String::from("\\anew{ \\bob{ content }");
-        let result = find_deletions(&text, vec!["anew", "bob"], None, None);
-        assert!(result.is_err());
-    }
-
-    #[test]
-    fn test_mismatched_in_nested() {
-        let text = String::from("\\anew{outer \\bob{inner}");
-        let result = find_deletions(&text, vec!["anew", "bob"], None, None);
-        assert!(result.is_err());
-    }
+    fn test_find_markup() -> Result<()> {
+        let dir = TempDir::new("stats")?;
+        let text = "\\alice{a b}\n\\section{Intro}\n\\todo{c d e}\\alice{ü}\n";
+        let file = dir.write("paper.tex", text)?;
+        let cleaner = Cleaner::new().unwrap_command("alice").drop_command("todo");
+        let found = find_markup(&file, &cleaner, &[]);
+        let unchanged = std::fs::read_to_string(&file);
 
-    // ============ Real-world LaTeX patterns ============
-
-    #[test]
-    fn test_citation_inside_command() -> Result<()> {
-        let mut text = String::from("\\anew{as shown in~\\cite{smith2020}}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "as shown in~\\cite{smith2020}");
+        assert_eq!(unchanged?, text);
+        let found: Vec<(String, usize, usize, usize, Option<String>)> = found?
+            .into_iter()
+            .map(|f| (f.command, f.chars, f.words, f.line, f.section))
+            .collect();
+        let intro = Some("Intro".to_string());
+        assert_eq!(
+            found,
+            vec![
+                ("alice".to_string(), 3, 2, 1, None),
+                ("todo".to_string(), 5, 3, 3, intro.clone()),
+                ("alice".to_string(), 1, 1, 3, intro),
+            ]
+        );
         Ok(())
     }
 
     #[test]
-    fn test_footnote_inside_command() -> Result<()> {
-        let mut text = String::from("\\anew{text\\footnote{A footnote.}}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "text\\footnote{A footnote.}");
-        Ok(())
+    fn test_find_sections() {
+        let text = "\\chapter{Intro}\n% \\section{Commented}\n\\section* { Results }\\subsection[Short]{Long}\\section";
+        assert_eq!(
+            find_sections(text, &[]),
+            vec![
+                (0, "Intro".to_string()),
+                (38, "Results".to_string()),
+                (59, "Long".to_string()),
+            ]
+        );
     }
 
     #[test]
-    fn test_textbf_inside_command() -> Result<()> {
-        let mut text = String::from("\\anew{\\textbf{bold} and normal}");
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(text, "\\textbf{bold} and normal");
-        Ok(())
+    fn test_find_sections_in_verbatim() {
+        let text =
+            "\\begin{verbatim}\n\\section{A}\n\\end{verbatim}\\begin{code}\\section{B}\\end{code}";
+        assert!(find_sections(text, &["code".to_string()]).is_empty());
     }
 
-    #[test]
-    fn test_multiline_paragraph() -> Result<()> {
-        let mut text = String::from(
-            "\\anew{This is a long paragraph\nthat spans multiple lines\nand has various content.}",
-        );
-        clean(&mut text, vec!["anew"])?;
-        assert_eq!(
-            text,
-            "This is a long paragraph\nthat spans multiple lines\nand has various content."
-        );
-        Ok(())
-    }
+    // ============ Configuration ============
 
     #[test]
-    fn test_table_environment() -> Result<()> {
-        let mut text =
-            String::from("\\begin{tabular}{cc}\n\\anew{A} & B \\\\\nC & \\anew{D}\n\\end{tabular}");
-        clean(&mut text, vec!["anew"])?;
+    fn test_flags_override_config() -> Result<()> {
+        let args = [
+            "collclean",
+            "--dry=false",
+            "--strip-comments",
+            "paper.tex",
+            "alice",
+        ];
+        let matches = command().try_get_matches_from(args)?;
+        assert!(!flag(&matches, "dry", true));
+        assert!(flag(&matches, "strip-comments", false));
+        // not given on the command line
+        assert!(flag(&matches, "strip-dead", true));
+        assert!(!flag(&matches, "strip-dead", false));
+        // a flag does not take the file as its value
         assert_eq!(
-            text,
-            "\\begin{tabular}{cc}\nA & B \\\\\nC & D\n\\end{tabular}"
+            matches.get_one::<String>("FILE").map(String::as_str),
+            Some("paper.tex")
         );
         Ok(())
     }
 
     #[test]
-    fn test_itemize_environment() -> Result<()> {
-        let mut text = String::from(
-            "\\begin{itemize}\n\\item \\anew{First}\n\\item \\bob{Second}\n\\end{itemize}",
-        );
-        clean(&mut text, vec!["anew", "bob"])?;
-        assert_eq!(
-            text,
-            "\\begin{itemize}\n\\item First\n\\item Second\n\\end{itemize}"
-        );
+    fn test_stdin_path() -> Result<()> {
+        assert!(is_std(Path::new("-")));
+        assert!(!is_std(Path::new("./-")));
+        assert_eq!(expand_paths(&["-".to_string()])?, [PathBuf::from("-")]);
         Ok(())
     }
 }
@@ -0,0 +1,44 @@
+//! Helpers for tests which read and write files.
+
+use std::path::{Path, PathBuf};
+
+/// A directory in the system's temporary directory, which is removed when it is dropped, also
+/// if a test panics.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates the empty directory `collclean-<name>-<pid>`.
+    pub fn new(name: &str) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("collclean-{name}-{}", std::process::id()));
+        // left over from an aborted run
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+        Ok(TempDir(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    /// Writes `text` to the file `name`, creating its parent directories, and returns its path.
+    pub fn write(&self, name: &str, text: &str) -> std::io::Result<PathBuf> {
+        let file = self.join(name);
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&file, text)?;
+        Ok(file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,383 @@
+//! Splits LaTeX source into tokens, roughly following the category codes TeX uses in documents.
+
+use std::ops::Range;
+
+/// The category of a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Backslash followed by letters, e.g. `\alice`.
+    ControlWord,
+    /// Backslash followed by a single other character, e.g. `\{`, `\%` or `\\`.
+    ControlSymbol,
+    /// Opening bracket `{`.
+    BeginGroup,
+    /// Closing bracket `}`.
+    EndGroup,
+    /// Opening bracket `[` of an optional argument.
+    OpenBracket,
+    /// Closing bracket `]` of an optional argument.
+    CloseBracket,
+    /// `%` up to the end of the line, excluding the line break.
+    Comment,
+    /// Inline verbatim `\verb|...|` or the content of a verbatim environment.
+    Verbatim,
+    /// Spaces and tabs.
+    Whitespace,
+    /// Line break `\n` or `\r\n`.
+    Newline,
+    /// Any other characters.
+    Text,
+}
+
+/// A piece of the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    /// The source text of the token.
+    pub text: &'a str,
+    /// Byte offset of the first character.
+    pub start: usize,
+    /// Byte offset after the last character.
+    pub end: usize,
+    /// 0-indexed line of the first character.
+    pub line: usize,
+    /// 0-indexed column (in characters) of the first character.
+    pub column: usize,
+}
+
+impl<'a> Token<'a> {
+    /// Name of a control word or symbol without the backslash.
+    pub fn name(&self) -> &'a str {
+        self.text.strip_prefix('\\').unwrap_or(self.text)
+    }
+
+    pub fn is(&self, kind: TokenKind) -> bool {
+        self.kind == kind
+    }
+}
+
+pub struct Tokenizer<'a> {
+    text: &'a str,
+    pos: usize,
+    line: usize,
+    column: usize,
+    // environments whose content is a single `Verbatim` token
+    verbatim: Vec<String>,
+    // byte range of the content of the next verbatim environment
+    verbatim_body: Option<Range<usize>>,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Tokenizer {
+            text,
+            pos: 0,
+            line: 0,
+            column: 0,
+            verbatim: vec![],
+            verbatim_body: None,
+        }
+    }
+
+    /// Reads the content of these environments as a single `Verbatim` token, so that it is not
+    /// split into commands, groups and comments.
+    pub fn verbatim_environments<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.verbatim = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Byte range of the content of a verbatim environment whose `\begin` ends at `pos`, if it
+    /// is closed by its `\end`.
+    fn verbatim_body(&self, pos: usize) -> Option<Range<usize>> {
+        let rest = self.text[pos..].trim_start_matches(is_whitespace);
+        let after = rest.strip_prefix('{')?;
+        let close = after.find(['{', '}'])?;
+        let name = after[..close].trim();
+        if !after[close..].starts_with('}') || !self.verbatim.iter().any(|v| v == name) {
+            return None;
+        }
+        let start = self.text.len() - after.len() + close + 1;
+        let end = start + self.text[start..].find(&format!("\\end{{{name}}}"))?;
+        Some(start..end)
+    }
+
+    /// Length of `\verb<delim>...<delim>` at the start of `rest`, if it is closed on its line.
+    fn verbatim_len(rest: &str) -> Option<usize> {
+        let after = rest.strip_prefix("\\verb")?;
+        let after = after.strip_prefix('*').unwrap_or(after);
+        let delimiter = after
+            .chars()
+            .next()
+            .filter(|c| !c.is_ascii_alphabetic() && *c != '\n' && *c != '\r')?;
+        let body = &after[delimiter.len_utf8()..];
+        let close = body.find([delimiter, '\n'])?;
+        body[close..]
+            .starts_with(delimiter)
+            .then(|| rest.len() - body.len() + close + delimiter.len_utf8())
+    }
+}
+
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    Tokenizer::new(text).collect()
+}
+
+fn is_whitespace(c: char) -> bool {
+    c.is_whitespace() && c != '\n'
+}
+
+fn is_text(c: char) -> bool {
+    !matches!(c, '\\' | '{' | '}' | '[' | ']' | '%' | '\n') && !c.is_whitespace()
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let rest = &self.text[self.pos..];
+        let first = rest.chars().next()?;
+        let run = |f: fn(char) -> bool| rest.find(|c| !f(c)).unwrap_or(rest.len());
+
+        let verbatim_len = self
+            .verbatim_body
+            .take_if(|body| body.start == self.pos)
+            .map_or(0, |body| body.len());
+        let (kind, len) = match first {
+            _ if verbatim_len > 0 => (TokenKind::Verbatim, verbatim_len),
+            '\\' => {
+                let word = rest[1..]
+                    .find(|c: char| !c.is_ascii_alphabetic())
+                    .unwrap_or(rest.len() - 1);
+                if word > 0 {
+                    match Self::verbatim_len(rest) {
+                        Some(len) if &rest[1..=word] == "verb" => (TokenKind::Verbatim, len),
+                        _ => (TokenKind::ControlWord, 1 + word),
+                    }
+                } else {
+                    // a line break after a backslash is a token on its own
+                    let symbol = rest[1..]
+                        .chars()
+                        .next()
+                        .filter(|c| *c != '\n' && !rest[1..].starts_with("\r\n"))
+                        .map_or(0, char::len_utf8);
+                    (TokenKind::ControlSymbol, 1 + symbol)
+                }
+            }
+            '{' => (TokenKind::BeginGroup, 1),
+            '}' => (TokenKind::EndGroup, 1),
+            '[' => (TokenKind::OpenBracket, 1),
+            ']' => (TokenKind::CloseBracket, 1),
+            '%' => (
+                TokenKind::Comment,
+                rest.find('\n').map_or(rest.len(), |end| {
+                    // keep `\r\n` together
+                    if rest[..end].ends_with('\r') {
+                        end - 1
+                    } else {
+                        end
+                    }
+                }),
+            ),
+            '\n' => (TokenKind::Newline, 1),
+            '\r' if rest.starts_with("\r\n") => (TokenKind::Newline, 2),
+            c if is_whitespace(c) => {
+                // `\r` of a following `\r\n` belongs to the line break
+                let len = run(is_whitespace);
+                let len = if rest[len..].starts_with('\n') && rest[..len].ends_with('\r') {
+                    len - 1
+                } else {
+                    len
+                };
+                (TokenKind::Whitespace, len)
+            }
+            _ => (TokenKind::Text, run(is_text)),
+        };
+
+        let token = Token {
+            kind,
+            text: &rest[..len],
+            start: self.pos,
+            end: self.pos + len,
+            line: self.line,
+            column: self.column,
+        };
+        self.pos += len;
+        match token.text.rfind('\n') {
+            Some(newline) => {
+                self.line += token.text.matches('\n').count();
+                self.column = token.text[newline + 1..].chars().count();
+            }
+            None => self.column += token.text.chars().count(),
+        }
+        if kind == TokenKind::ControlWord && token.name() == "begin" && !self.verbatim.is_empty() {
+            self.verbatim_body = self.verbatim_body(self.pos);
+        }
+        Some(token)
+    }
+}
+
+#[cfg(test)]
+mod test_tokenizer {
+    use super::*;
+
+    fn kinds(text: &str) -> Vec<(TokenKind, &str)> {
+        Tokenizer::new(text).map(|t| (t.kind, t.text)).collect()
+    }
+
+    #[test]
+    fn test_control_words_and_symbols() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("\\alice{x}\\\\{\\{y\\}"),
+            vec![
+                (ControlWord, "\\alice"),
+                (BeginGroup, "{"),
+                (Text, "x"),
+                (EndGroup, "}"),
+                (ControlSymbol, "\\\\"),
+                (BeginGroup, "{"),
+                (ControlSymbol, "\\{"),
+                (Text, "y"),
+                (ControlSymbol, "\\}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control_word_ends_at_non_letter() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("\\rev1 \\ä\\"),
+            vec![
+                (ControlWord, "\\rev"),
+                (Text, "1"),
+                (Whitespace, " "),
+                (ControlSymbol, "\\ä"),
+                (ControlSymbol, "\\"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("a\\% b % c {\r\n\\\\%d"),
+            vec![
+                (Text, "a"),
+                (ControlSymbol, "\\%"),
+                (Whitespace, " "),
+                (Text, "b"),
+                (Whitespace, " "),
+                (Comment, "% c {"),
+                (Newline, "\r\n"),
+                (ControlSymbol, "\\\\"),
+                (Comment, "%d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_brackets_and_whitespace() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("[a, b]\t \r\n\\\n"),
+            vec![
+                (OpenBracket, "["),
+                (Text, "a,"),
+                (Whitespace, " "),
+                (Text, "b"),
+                (CloseBracket, "]"),
+                (Whitespace, "\t "),
+                (Newline, "\r\n"),
+                (ControlSymbol, "\\"),
+                (Newline, "\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verb() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("\\verb|}%|\\verb*+a+\\verbatim\\verb|x\n|"),
+            vec![
+                (Verbatim, "\\verb|}%|"),
+                (Verbatim, "\\verb*+a+"),
+                (ControlWord, "\\verbatim"),
+                (ControlWord, "\\verb"),
+                (Text, "|x"),
+                (Newline, "\n"),
+                (Text, "|"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verbatim_environments() {
+        use TokenKind::*;
+        let kinds = |text| -> Vec<(TokenKind, &str)> {
+            Tokenizer::new(text)
+                .verbatim_environments(["verbatim"])
+                .map(|t| (t.kind, t.text))
+                .collect()
+        };
+        assert_eq!(
+            kinds("\\begin {verbatim}1%\n}\\end{verbatim}%"),
+            vec![
+                (ControlWord, "\\begin"),
+                (Whitespace, " "),
+                (BeginGroup, "{"),
+                (Text, "verbatim"),
+                (EndGroup, "}"),
+                (Verbatim, "1%\n}"),
+                (ControlWord, "\\end"),
+                (BeginGroup, "{"),
+                (Text, "verbatim"),
+                (EndGroup, "}"),
+                (Comment, "%"),
+            ]
+        );
+        assert_eq!(kinds("\\begin{verbatim}\\end{verbatim}").len(), 8);
+        assert_eq!(kinds("\\begin{verbatim}%")[4], (Comment, "%"));
+        assert_eq!(kinds("\\begin{note}%")[4], (Comment, "%"));
+
+        let tokens: Vec<Token> = Tokenizer::new("\\begin{verbatim}a\nbc\\end{verbatim}")
+            .verbatim_environments(["verbatim"])
+            .collect();
+        assert_eq!((tokens[5].line, tokens[5].column), (1, 2));
+    }
+
+    #[test]
+    fn test_positions() {
+        let tokens: Vec<Token> = Tokenizer::new("é \\a\n  {b}").collect();
+        let positions: Vec<(usize, usize, usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.start, t.end, t.line, t.column))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                (0, 2, 0, 0),
+                (2, 3, 0, 1),
+                (3, 5, 0, 2),
+                (5, 6, 0, 4),
+                (6, 8, 1, 0),
+                (8, 9, 1, 2),
+                (9, 10, 1, 3),
+                (10, 11, 1, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unicode_text() {
+        let text = "中文 🎉{ü}";
+        let tokens: Vec<Token> = Tokenizer::new(text).collect();
+        assert_eq!(tokens.iter().map(|t| t.text).collect::<String>(), text);
+        assert_eq!(tokens[2].text, "🎉");
+        assert_eq!(tokens[3].column, 4);
+    }
+}